@@ -0,0 +1,140 @@
+use encoding_rs::*;
+
+use crate::config;
+use crate::efd_contribuicoes::Apuracao;
+use crate::retificadora;
+
+use std::fs;
+use std::path::Path;
+
+const DESCR_AJ_BC: &str = "Exclusão do ICMS da base de cálculo (RE 574.706)";
+
+// ICMS excluded from an M210/M610 line, and the share of each branch in it.
+pub(crate) struct Exclusao<'a> {
+    pub(crate) reg: &'static str,
+    pub(crate) apuracao: &'a Apuracao,
+    pub(crate) vl_icms: f64,
+    pub(crate) filiais: Vec<(String, f64)>,
+}
+
+pub(crate) fn valor(value: f64) -> String {
+    format!("{:.2}", value).replace('.', ",")
+}
+
+impl Exclusao<'_> {
+    // M215/M615 reductions (IND_AJ_BC 0) of the line, one per branch, with their rounded values.
+    // DT_REF is the end date (ddmmaaaa) of the period.
+    pub(crate) fn ajustes(&self, config: &config::Ajuste, competencia: &str) -> Vec<(f64, String)> {
+        let mut ajustes: Vec<(f64, String)> = Vec::new();
+
+        for (cnpj, proporcao) in self.filiais.iter() {
+            let vl_aj_bc = (self.vl_icms * proporcao * 100_f64).round() / 100_f64;
+            if vl_aj_bc <= 0_f64 {
+                continue;
+            }
+
+            ajustes.push((
+                vl_aj_bc,
+                format!(
+                    "|{}|0|{}|{}||{}|{}|{}|{}||",
+                    self.reg,
+                    valor(vl_aj_bc),
+                    config.cod_aj_bc,
+                    DESCR_AJ_BC,
+                    competencia,
+                    config.cod_cta,
+                    cnpj
+                ),
+            ));
+        }
+
+        ajustes
+    }
+}
+
+// Each M210/M610 line, recalculated with the reduction, followed by its M215/M615 reductions, in
+// the layout of the EFD Contribuições. Lines in the layout before 2019, which has no M215/M615,
+// are left out.
+pub(crate) fn registros(
+    config: &config::Ajuste,
+    competencia: &str,
+    exclusoes: &[Exclusao],
+) -> Vec<String> {
+    let mut registros: Vec<String> = Vec::new();
+
+    for exclusao in exclusoes.iter().filter(|e| !e.apuracao.layout_antigo()) {
+        let ajustes = exclusao.ajustes(config, competencia);
+        if ajustes.is_empty() {
+            continue;
+        }
+
+        let mut campos: Vec<String> = exclusao
+            .apuracao
+            .linha
+            .split('|')
+            .map(|s| s.to_string())
+            .collect();
+        retificadora::apurar(
+            &mut campos,
+            ajustes.iter().map(|(vl_aj_bc, _)| vl_aj_bc).sum(),
+        );
+
+        registros.push(campos.join("|"));
+        registros.extend(ajustes.into_iter().map(|(_, registro)| registro));
+    }
+
+    registros
+}
+
+// Written in the encoding of the EFD files, with CRLF line endings.
+pub(crate) fn gravar(path: &Path, registros: &[String]) {
+    let mut text = registros.join("\r\n");
+    text.push_str("\r\n");
+
+    let (bytes, _, _) = WINDOWS_1252.encode(&text);
+    fs::write(path, bytes).expect("Não foi possível gravar os registros de ajuste.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registros_trazem_o_m210_recalculado_e_a_data_de_referencia() {
+        let apuracao = Apuracao {
+            cod_cont: "01".to_string(),
+            vl_rec_brt: 100000_f64,
+            vl_bc_cont: 100000_f64,
+            aliq_cont: 1.65,
+            linha: "|M210|01|100000,00|100000,00|0|0|100000,00|1,6500|0|0|1650,00|0|0|0|0|1650,00|"
+                .to_string(),
+            numero: 4,
+            vl_ajus_icms: 0_f64,
+        };
+        let exclusoes = [Exclusao {
+            reg: "M215",
+            apuracao: &apuracao,
+            vl_icms: 10000_f64,
+            filiais: vec![
+                ("11111111000191".to_string(), 0.75),
+                ("11111111000272".to_string(), 0.25),
+            ],
+        }];
+        let config = config::Ajuste {
+            cod_aj_bc: "41".to_string(),
+            cod_cta: "3.1.1.01.001".to_string(),
+            retificadora: false,
+        };
+
+        let registros = registros(&config, "31012023", &exclusoes);
+
+        assert_eq!(
+            registros,
+            [
+                "|M210|01|100000,00|100000,00|0|10000,00|90000,00|1,6500|0|0|1485,00|0|0|0|0|1485,00|",
+                "|M215|0|7500,00|41||Exclusão do ICMS da base de cálculo (RE 574.706)|31012023|3.1.1.01.001|11111111000191||",
+                "|M215|0|2500,00|41||Exclusão do ICMS da base de cálculo (RE 574.706)|31012023|3.1.1.01.001|11111111000272||",
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Default)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub(crate) f100: Vec<F100Rule>,
+
+    #[serde(default)]
+    pub(crate) monofasico: Monofasico,
+
+    #[serde(default)]
+    pub(crate) exclusao: Exclusao,
+
+    pub(crate) selic: Option<Selic>,
+
+    pub(crate) ajuste: Option<Ajuste>,
+
+    pub(crate) perdcomp: Option<Perdcomp>,
+
+    // Debts declared in DCTF/DCTFWeb, as a CSV extract or in the text layout, to compare with
+    // M200/M600.
+    pub(crate) dctf: Option<PathBuf>,
+
+    // Ledger of the compensations made with the credits, one YAML file per CNPJ root.
+    pub(crate) razao: Option<Razao>,
+
+    // Receipt number of each transmitted EFD Contribuições, keyed by file name, to chain the
+    // retifying files through NUM_REC_ANTERIOR.
+    #[serde(default)]
+    pub(crate) recibos: HashMap<String, String>,
+
+    // Claim profile of each client, keyed by CNPJ root.
+    #[serde(default)]
+    pub(crate) acoes: HashMap<String, Acao>,
+}
+
+// Filing date ("dd/mm/aaaa") of the lawsuit or administrative claim. `anterior_modulacao` defaults
+// to whether it was filed until 15/03/2017. `transito_em_julgado` starts the five years to use
+// the credit.
+#[derive(Deserialize)]
+pub(crate) struct Acao {
+    pub(crate) ajuizamento: String,
+    pub(crate) anterior_modulacao: Option<bool>,
+    pub(crate) transito_em_julgado: Option<String>,
+}
+
+// How the ICMS is attributed to the M210/M610 lines: in proportion to their gross revenue, or by
+// the CST and rate of each item, consolidation and F block line (the other registers are still
+// allocated in proportion).
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Exclusao {
+    #[default]
+    Proporcional,
+    Item,
+}
+
+// M215/M615 adjustments of the exclusion: the code of table 4.3.18 and the account to book them to.
+#[derive(Deserialize)]
+pub(crate) struct Ajuste {
+    #[serde(default = "cod_aj_bc")]
+    pub(crate) cod_aj_bc: String,
+    pub(crate) cod_cta: String,
+
+    // Also write the EFD Contribuições as a retifying file with the adjustments applied.
+    #[serde(default)]
+    pub(crate) retificadora: bool,
+}
+
+fn cod_aj_bc() -> String {
+    "41".to_string()
+}
+
+// Export of the recovered amounts for the PER/DCOMP, optionally reconciled with a CSV of the DARF
+// payments. The file goes to `pasta` or, by default, next to the EFD Contribuições.
+#[derive(Deserialize)]
+pub(crate) struct Perdcomp {
+    pub(crate) darf: Option<PathBuf>,
+    pub(crate) pasta: Option<PathBuf>,
+}
+
+// Credit utilization ledger of each company, kept in `pasta` or, by default, next to the EFD
+// Contribuições. The balance is shown at `data` ("dd/mm/aaaa"), by default the date the SELIC
+// credits are corrected to.
+#[derive(Deserialize)]
+pub(crate) struct Razao {
+    pub(crate) data: Option<String>,
+    pub(crate) pasta: Option<PathBuf>,
+}
+
+// Monthly SELIC table (CSV or YAML) and the month ("mm/aaaa") to correct the credits to. Without
+// `data`, credits are corrected to the month after the last rate of the table.
+#[derive(Deserialize)]
+pub(crate) struct Selic {
+    pub(crate) tabela: PathBuf,
+    pub(crate) data: Option<String>,
+}
+
+// Monophasic CST 01 items are only reported, unless `reclassificar` is set, in which case their
+// revenue is left out of the exclusion. `ncm` adds prefixes to the built-in table.
+#[derive(Deserialize, Default)]
+pub(crate) struct Monofasico {
+    #[serde(default)]
+    pub(crate) reclassificar: bool,
+
+    #[serde(default)]
+    pub(crate) ncm: Vec<String>,
+}
+
+// A rule matches an F100 line when every field that is set is equal to the line's value.
+// The first matching rule wins.
+#[derive(Deserialize)]
+pub(crate) struct F100Rule {
+    pub(crate) nat_bc_cred: Option<String>,
+    pub(crate) ind_oper: Option<String>,
+    pub(crate) cst: Option<String>,
+    pub(crate) cod_cta: Option<String>,
+
+    // Lines matched by a rule without `icms` are not ICMS-bearing.
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub(crate) icms: Option<F100Icms>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum F100Icms {
+    Aliquota(f64),
+    EfdIcmsIpi { cod_mod: String, cfop: String },
+}
+
+impl F100Rule {
+    pub(crate) fn matches(
+        &self,
+        nat_bc_cred: &str,
+        ind_oper: &str,
+        cst: &str,
+        cod_cta: &str,
+    ) -> bool {
+        let eq = |rule: &Option<String>, value: &str| match rule {
+            Some(r) => r == value,
+            None => true,
+        };
+
+        eq(&self.nat_bc_cred, nat_bc_cred)
+            && eq(&self.ind_oper, ind_oper)
+            && eq(&self.cst, cst)
+            && eq(&self.cod_cta, cod_cta)
+    }
+}
+
+// A missing file means the default options; an invalid one is reported to the user.
+pub(crate) fn load(path: &Path) -> Result<Config, String> {
+    match File::open(path) {
+        Ok(file) => serde_yaml::from_reader(file).map_err(|erro| {
+            format!(
+                "Arquivo de configuração inválido ({}): {}. A análise seguirá sem as opções.",
+                path.display(),
+                erro
+            )
+        }),
+        Err(_) => Ok(Config::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The rules of the README.
+    const F100: &str = r#"
+f100:
+  - ind_oper: "1"
+    cod_cta: "3.01.01.001"
+    icms:
+      aliquota: 18.0
+  - ind_oper: "1"
+    cst: "01"
+    icms:
+      efd_icms_ipi:
+        cod_mod: "55"
+        cfop: "5102"
+  - ind_oper: "2"
+"#;
+
+    // The `icms` of the first rule matching the line, as in the analysis of F100.
+    fn icms<'a>(
+        config: &'a Config,
+        ind_oper: &str,
+        cst: &str,
+        cod_cta: &str,
+    ) -> Option<Option<&'a F100Icms>> {
+        config
+            .f100
+            .iter()
+            .find(|rule| rule.matches("", ind_oper, cst, cod_cta))
+            .map(|rule| rule.icms.as_ref())
+    }
+
+    #[test]
+    fn f100_aplica_a_primeira_regra_que_corresponde() {
+        let config: Config = serde_yaml::from_str(F100).unwrap();
+
+        assert!(matches!(
+            icms(&config, "1", "01", "3.01.01.001"),
+            Some(Some(F100Icms::Aliquota(aliquota))) if *aliquota == 18.0
+        ));
+        assert!(matches!(
+            icms(&config, "1", "01", "3.01.02.001"),
+            Some(Some(F100Icms::EfdIcmsIpi { cod_mod, cfop })) if cod_mod == "55" && cfop == "5102"
+        ));
+        // Matched by a rule without `icms`.
+        assert!(matches!(icms(&config, "2", "01", ""), Some(None)));
+        assert!(icms(&config, "1", "02", "3.01.02.001").is_none());
+    }
+
+    #[test]
+    fn f100_campos_omitidos_aceitam_qualquer_valor() {
+        let rule = F100Rule {
+            nat_bc_cred: None,
+            ind_oper: Some("1".to_string()),
+            cst: None,
+            cod_cta: None,
+            icms: None,
+        };
+
+        assert!(rule.matches("01", "1", "05", "3.01"));
+        assert!(!rule.matches("01", "2", "05", "3.01"));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::efd_contribuicoes::to_f64;
+
+use std::collections::HashMap;
+
+// Since 05/2023 (Lei 14.592/2023), the ICMS on purchases is excluded from the base of PIS/COFINS
+// credits.
+pub(crate) const INICIO: &str = "202305";
+
+const CST_CREDITO: [&str; 7] = ["50", "51", "52", "53", "54", "55", "56"];
+
+// Reduction of the PIS/COFINS credits of the purchases of a branch, register and CST.
+#[derive(Default)]
+pub struct Reducao {
+    pub(crate) vl_icms: f64,
+    pub(crate) pis: f64,
+    pub(crate) cofins: f64,
+}
+
+// C191/C195 lines of a CST: VL_ITEM, VL_ITEM * ALIQ, and the part of VL_ITEM left out of VL_BC.
+#[derive(Default)]
+struct C191Cst {
+    vl_item: f64,
+    vl_item_aliq: f64,
+    vl_excluido: f64,
+}
+
+#[derive(Default)]
+struct C191Value {
+    vl_item: f64,
+    vl_item_cst: HashMap<String, C191Cst>,
+}
+
+// Purchases of EFD Contribuições, read in the same pass as the revenue.
+//
+// C170 items carry their own ICMS. C191/C195 lines don't, so the inbound ICMS of the CFOP in
+// EFD ICMS/IPI, less the ICMS of the C170 items with that CFOP, is split across them by VL_ITEM.
+//
+// The taxpayer may already have left the ICMS out of the credit base, in which case VL_BC falls
+// short of VL_ITEM less VL_DESC by up to the ICMS, and only the rest of the ICMS is reduced.
+#[derive(Default)]
+pub(crate) struct Compras {
+    summary: HashMap<(String, String, String), Reducao>,
+    c170_vl_icms: HashMap<(String, String), f64>,
+    c191_cache: HashMap<(String, String), C191Value>,
+    c195_cache: HashMap<(String, String), C191Value>,
+}
+
+// ICMS of an item still in its credit base.
+fn nao_excluido(vl_icms: f64, vl_item: f64, vl_desc: f64, vl_bc: f64) -> f64 {
+    let excluido: f64 = (vl_item - vl_desc - vl_bc).clamp(0_f64, vl_icms);
+    (vl_icms - excluido).min(vl_bc)
+}
+
+impl Compras {
+    // A C170 item of an inbound C100 (IND_OPER 0).
+    pub(crate) fn c170(&mut self, cnpj: &str, r: &[String]) {
+        let cfop: String = r.get(11).unwrap().to_string();
+        let vl_item: f64 = to_f64(&r.get(7));
+        let vl_desc: f64 = to_f64(&r.get(8));
+        let vl_icms: f64 = to_f64(&r.get(15));
+
+        *self
+            .c170_vl_icms
+            .entry((cnpj.to_string(), cfop))
+            .or_insert(0_f64) += vl_icms;
+
+        let cst_pis: &str = r.get(25).unwrap();
+        let cst_cofins: &str = r.get(31).unwrap();
+
+        if CST_CREDITO.contains(&cst_pis) {
+            let vl_icms = nao_excluido(vl_icms, vl_item, vl_desc, to_f64(&r.get(26)));
+            let key = (cnpj.to_string(), "C170".to_string(), cst_pis.to_string());
+            let value = self.summary.entry(key).or_default();
+            value.vl_icms += vl_icms;
+            value.pis += vl_icms * to_f64(&r.get(27)) / 100_f64;
+        }
+
+        if CST_CREDITO.contains(&cst_cofins) {
+            let vl_icms = nao_excluido(vl_icms, vl_item, vl_desc, to_f64(&r.get(32)));
+            let key = (cnpj.to_string(), "C170".to_string(), cst_cofins.to_string());
+            let value = self.summary.entry(key).or_default();
+            value.cofins += vl_icms * to_f64(&r.get(33)) / 100_f64;
+        }
+    }
+
+    // A C191 (PIS) or C195 (COFINS) line of the purchases consolidated in C190.
+    pub(crate) fn c191(&mut self, reg: &str, cnpj: &str, r: &[String]) {
+        let cst: String = r.get(3).unwrap().to_string();
+        let cfop: String = r.get(4).unwrap().to_string();
+        let vl_item: f64 = to_f64(&r.get(5));
+        let vl_desc: f64 = to_f64(&r.get(6));
+        let vl_bc: f64 = to_f64(&r.get(7));
+        let aliq: f64 = to_f64(&r.get(8));
+
+        if !CST_CREDITO.contains(&cst.as_str()) {
+            return;
+        }
+
+        let cache = if reg == "C191" {
+            &mut self.c191_cache
+        } else {
+            &mut self.c195_cache
+        };
+
+        let value = cache.entry((cnpj.to_string(), cfop)).or_default();
+        value.vl_item += vl_item;
+        let item = value.vl_item_cst.entry(cst).or_default();
+        item.vl_item += vl_item;
+        item.vl_item_aliq += vl_item * aliq;
+        item.vl_excluido += (vl_item - vl_desc - vl_bc).max(0_f64);
+    }
+
+    pub(crate) fn reducoes(
+        self,
+        efd_icms_ipi: &HashMap<(String, String, String), f64>,
+    ) -> HashMap<(String, String, String), Reducao> {
+        let mut summary = self.summary;
+
+        for (reg, cache) in [("C191", self.c191_cache), ("C195", self.c195_cache)] {
+            for (key, value) in cache {
+                let vl_icms_cfop: f64 = efd_icms_ipi
+                    .iter()
+                    .filter(|(k, _)| k.0 == key.0 && k.2 == key.1)
+                    .fold(0_f64, |acc, (_, v)| acc + v);
+                let vl_icms_c170: f64 = *self.c170_vl_icms.get(&key).unwrap_or(&0_f64);
+                let vl_icms: f64 = (vl_icms_cfop - vl_icms_c170).max(0_f64);
+
+                if vl_icms == 0_f64 || value.vl_item == 0_f64 {
+                    continue;
+                }
+
+                for (cst, item) in value.vl_item_cst {
+                    let vl_icms_cst = vl_icms * item.vl_item / value.vl_item;
+                    let vl_icms_cst = (vl_icms_cst - item.vl_excluido).max(0_f64);
+                    if vl_icms_cst == 0_f64 {
+                        continue;
+                    }
+
+                    let reducao = summary
+                        .entry((key.0.clone(), reg.to_string(), cst))
+                        .or_default();
+                    let reducao_cst = vl_icms_cst * item.vl_item_aliq / item.vl_item / 100_f64;
+
+                    if reg == "C191" {
+                        reducao.vl_icms += vl_icms_cst;
+                        reducao.pis += reducao_cst;
+                    } else {
+                        reducao.cofins += reducao_cst;
+                    }
+                }
+            }
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linha(texto: &str) -> Vec<String> {
+        texto.split('|').map(|s| s.to_string()).collect()
+    }
+
+    // C170 of a purchase: VL_ITEM 1000, VL_ICMS 180, CST 50 at 1,65% and 7,6%.
+    fn c170(vl_bc: &str) -> Vec<String> {
+        linha(&format!(
+            "|C170|1|ITEM||1|UN|1000,00|0|0|000|1102||1000,00|18,00|180,00|0|0|0|0||||||50|{}|1,65|||0|50|{}|7,60|||0||",
+            vl_bc, vl_bc
+        ))
+    }
+
+    #[test]
+    fn c170_reduz_o_icms_da_base() {
+        let mut compras = Compras::default();
+        compras.c170("11111111000191", &c170("1000,00"));
+
+        let reducao = compras.reducoes(&HashMap::new());
+        let value = &reducao[&(
+            "11111111000191".to_string(),
+            "C170".to_string(),
+            "50".to_string(),
+        )];
+
+        assert!((value.vl_icms - 180_f64).abs() < 1e-9);
+        assert!((value.pis - 2.97_f64).abs() < 1e-9);
+        assert!((value.cofins - 13.68_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn c170_com_base_ja_reduzida_nao_reduz_de_novo() {
+        let mut compras = Compras::default();
+        compras.c170("11111111000191", &c170("820,00"));
+
+        let reducao = compras.reducoes(&HashMap::new());
+        let value = &reducao[&(
+            "11111111000191".to_string(),
+            "C170".to_string(),
+            "50".to_string(),
+        )];
+
+        assert!(value.vl_icms.abs() < 1e-9);
+        assert!(value.pis.abs() < 1e-9);
+        assert!(value.cofins.abs() < 1e-9);
+    }
+
+    #[test]
+    fn c191_rateia_o_icms_do_cfop_sem_o_dos_itens() {
+        let cnpj = "11111111000191".to_string();
+        let mut compras = Compras::default();
+        compras.c170(&cnpj, &c170("1000,00"));
+        compras.c191(
+            "C191",
+            &cnpj,
+            &linha("|C191|11111111000272|50|1102|3000,00|0|3000,00|1,65||||"),
+        );
+        compras.c191(
+            "C191",
+            &cnpj,
+            &linha("|C191|11111111000272|50|1102|1000,00|0|820,00|1,65||||"),
+        );
+
+        let efd_icms_ipi = HashMap::from([(
+            (cnpj.clone(), "55".to_string(), "1102".to_string()),
+            900_f64,
+        )]);
+        let reducao = compras.reducoes(&efd_icms_ipi);
+        let value = &reducao[&(cnpj, "C191".to_string(), "50".to_string())];
+
+        // 900 less the 180 of C170, less the 180 already out of the base of the second line.
+        assert!((value.vl_icms - 540_f64).abs() < 1e-9);
+        assert!((value.pis - 8.91_f64).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,149 @@
+use encoding_rs::*;
+
+use crate::efd_contribuicoes::decimal;
+use crate::selic;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Debts declared in DCTF/DCTFWeb by CNPJ root, period (aaaamm) and revenue code.
+pub(crate) type Debitos = HashMap<(String, String, String), f64>;
+
+// Fields of the R10 debt record of the DCTF text layout: start (from 0) and size. The period is
+// mmaaaa, the code carries its variation and the value has two implied decimals.
+const R10_CNPJ: (usize, usize) = (3, 14);
+const R10_PERIODO: (usize, usize) = (17, 6);
+const R10_CODIGO: (usize, usize) = (23, 6);
+const R10_VALOR: (usize, usize) = (29, 14);
+
+// Loads the debts from a CSV extract or from a file in the DCTF text layout, told apart line by
+// line.
+pub(crate) fn load(path: &Path) -> Result<Debitos, String> {
+    let bytes =
+        fs::read(path).map_err(|_| "Não foi possível ler o extrato da DCTF.".to_string())?;
+    let (text, _, _) = WINDOWS_1252.decode(&bytes);
+
+    ler(&text)
+}
+
+fn campo(line: &str, (inicio, tamanho): (usize, usize)) -> Option<&str> {
+    line.get(inicio..inicio + tamanho)
+}
+
+// An R10 record: CNPJ, period, code and value.
+fn r10(line: &str) -> Option<(&str, String, &str, f64)> {
+    let periodo = campo(line, R10_PERIODO)?;
+
+    let valor = campo(line, R10_VALOR)?;
+    if !valor.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((
+        campo(line, R10_CNPJ)?,
+        format!("{}/{}", periodo.get(..2)?, periodo.get(2..)?),
+        campo(line, R10_CODIGO)?,
+        valor.parse::<f64>().ok()? / 100_f64,
+    ))
+}
+
+// A CSV line "cnpj;mm/aaaa;código;valor".
+fn csv(line: &str) -> Option<(&str, String, &str, f64)> {
+    let data: Vec<&str> = line.split(';').map(str::trim).collect();
+
+    Some((
+        data.first()?,
+        data.get(1)?.to_string(),
+        data.get(2)?,
+        decimal(data.get(3)?)?,
+    ))
+}
+
+// The CNPJ may be formatted, and only the first four digits of the code (without the variation)
+// are kept. A file with text that has no debt in either layout is rejected rather than compared as
+// if nothing had been declared.
+fn ler(text: &str) -> Result<Debitos, String> {
+    let mut debitos: Debitos = HashMap::new();
+
+    for line in text.lines() {
+        let debito = if line.starts_with("R10") && !line.contains(';') {
+            r10(line)
+        } else {
+            csv(line)
+        };
+
+        let Some((cnpj, periodo, codigo, valor)) = debito else {
+            continue;
+        };
+
+        let raiz: String = cnpj.chars().filter(char::is_ascii_digit).take(8).collect();
+
+        let (8, Some(periodo), Some(codigo)) = (
+            raiz.len(),
+            selic::mes(&periodo).map(|mes| format!("{}{:02}", mes / 12, mes % 12 + 1)),
+            codigo.get(..4),
+        ) else {
+            continue;
+        };
+
+        *debitos
+            .entry((raiz, periodo, codigo.to_string()))
+            .or_insert(0_f64) += valor;
+    }
+
+    if debitos.is_empty() && !text.trim().is_empty() {
+        return Err(
+            "O extrato da DCTF não tem débitos em CSV (cnpj;mm/aaaa;código;valor) nem registros R10 do leiaute texto."
+                .to_string(),
+        );
+    }
+
+    Ok(debitos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chave(periodo: &str, codigo: &str) -> (String, String, String) {
+        (
+            "11111111".to_string(),
+            periodo.to_string(),
+            codigo.to_string(),
+        )
+    }
+
+    #[test]
+    fn csv_soma_por_raiz_periodo_e_codigo() {
+        let debitos = ler("CNPJ;Período;Código;Valor\n\
+             11.111.111/0001-91;01/2023;6912-01;1.000,00\n\
+             11111111000272;01/2023;6912;650.00\n\
+             11111111000191;02/2023;5856;7600,00\n")
+        .unwrap();
+
+        assert_eq!(debitos.len(), 2);
+        assert!((debitos[&chave("202301", "6912")] - 1650_f64).abs() < 1e-9);
+        assert!((debitos[&chave("202302", "5856")] - 7600_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn leiaute_texto_le_os_registros_r10() {
+        let debitos = ler("DCTFM20230000011111111000191202301000000000000000000\n\
+             R101111111100019101202369120100000000165000\n\
+             R101111111100027201202369120100000000020050\n\
+             R101111111100019101202358560100000000760000\n\
+             R11111111110001910120230000000000000000000000\n")
+        .unwrap();
+
+        assert_eq!(debitos.len(), 2);
+        assert!((debitos[&chave("202301", "6912")] - 1850.5).abs() < 1e-9);
+        assert!((debitos[&chave("202301", "5856")] - 7600_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn arquivo_sem_debitos_e_recusado() {
+        assert!(ler("Relatório da DCTF\nsem débitos\n").is_err());
+        assert!(ler("").unwrap().is_empty());
+    }
+}
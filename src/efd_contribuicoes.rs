@@ -1,45 +1,395 @@
 use encoding_rs::*;
 use encoding_rs_io::DecodeReaderBytesBuilder;
 
+use crate::config::{Config, F100Icms};
+use crate::credito::{self, Compras};
+use crate::monofasico::{self, Classificador};
+use crate::perdcomp;
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
+// CSTs of revenue taxed at a percentage rate, whose base includes the ICMS. CST 03 is taxed per
+// unit of product (ad rem), so the ICMS doesn't change the contribution.
+pub(crate) const CST_TRIBUTADO: [&str; 3] = ["01", "02", "05"];
+
+// Registers (as labelled in the summary) whose ICMS is also split by CST and rate, for the
+// item-level exclusion.
+pub(crate) const REG_ITEM: [&str; 6] = ["C170", "C180", "C400", "F100", "F500", "F550"];
+
+// Revenue of a group by CST and rate of one contribution, to split the ICMS of the group.
+#[derive(Default)]
+struct Receita {
+    vl_opr: f64,
+    vl_opr_aliq: HashMap<(String, String), f64>,
+}
+
+impl Receita {
+    fn add(&mut self, cst: &str, aliq: f64, vl_opr: f64) {
+        self.vl_opr += vl_opr;
+        if CST_TRIBUTADO.contains(&cst) {
+            *self
+                .vl_opr_aliq
+                .entry((cst.to_string(), aliquota(aliq)))
+                .or_insert(0_f64) += vl_opr;
+        }
+    }
+
+    fn ratear(self, cnpj: &str, tributo: &str, vl_icms: f64, itens: &mut Itens) {
+        if self.vl_opr == 0_f64 {
+            return;
+        }
+
+        for ((cst, aliq), vl_opr_aliq) in self.vl_opr_aliq {
+            let key = (cnpj.to_string(), tributo.to_string(), cst, aliq);
+            *itens.entry(key).or_insert(0_f64) += vl_icms / self.vl_opr * vl_opr_aliq;
+        }
+    }
+}
+
+// ICMS by branch, contribution ("PIS" or "COFINS"), CST and rate.
+pub(crate) type Itens = HashMap<(String, String, String, String), f64>;
+
+// Rates are compared as text, so that they can be used as keys.
+pub(crate) fn aliquota(aliq: f64) -> String {
+    format!("{:.4}", aliq)
+}
+
 struct C175Value {
+    cod_mod: String,
     vl_icms: f64,
     vl_opr_cfop5102: f64,
-    vl_opr_cfop5102_cst01: f64,
+    vl_opr_cfop5102_cst: HashMap<String, f64>,
 }
 
 struct C180Value {
-    vl_opr_cfop5102: f64,
-    vl_opr_cfop5102_cst01: f64,
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    pis: Receita,
+    cofins: Receita,
 }
 
 struct C400Value {
     vl_opr: f64,
-    vl_opr_cst01: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    pis: Receita,
+    cofins: Receita,
+}
+
+struct C870Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    vl_opr_cst_cofins: HashMap<String, f64>,
+}
+
+struct D200Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    vl_opr_cofins: f64,
+    vl_opr_cst_cofins: HashMap<String, f64>,
+}
+
+struct D300Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    vl_opr_cst_cofins: HashMap<String, f64>,
+}
+
+struct D350Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    vl_opr_cst_cofins: HashMap<String, f64>,
+}
+
+struct D600Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    vl_opr_cofins: f64,
+    vl_opr_cst_cofins: HashMap<String, f64>,
+}
+
+// F500 and F550 have the same layout.
+struct F500Value {
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+    pis: Receita,
+    cofins: Receita,
+}
+
+pub struct F100Linha {
+    pub(crate) cnpj: String,
+    pub(crate) cst: String,
+    pub(crate) cod_cta: String,
+    pub(crate) vl_oper: f64,
+    pub(crate) vl_icms: Option<f64>,
+    pub(crate) aliq_pis: f64,
+    pub(crate) aliq_cofins: f64,
 }
 
 pub struct Apuracao {
+    pub(crate) cod_cont: String,
     pub(crate) vl_rec_brt: f64,
     pub(crate) vl_bc_cont: f64,
     pub(crate) aliq_cont: f64,
+    // The register as read, to place the adjustments generated for it, and its line in the file
+    // (from 0).
+    pub(crate) linha: String,
+    pub(crate) numero: usize,
+    // ICMS already excluded by the taxpayer through M215/M615.
+    pub(crate) vl_ajus_icms: f64,
+}
+
+// COD_CONT of the cumulative regime (table 4.3.5), summed in VL_TOT_CONT_CUM_PER of M200/M600.
+pub(crate) fn cumulativo(cod_cont: &str) -> bool {
+    cod_cont.starts_with('5') || cod_cont == "72"
+}
+
+impl Apuracao {
+    pub(crate) fn cumulativo(&self) -> bool {
+        cumulativo(&self.cod_cont)
+    }
+
+    // Layout before 2019, without the base adjustments and M215/M615.
+    pub(crate) fn layout_antigo(&self) -> bool {
+        self.linha.split('|').count() == 15
+    }
+
+    // CST of the revenue behind each COD_CONT (table 4.3.5).
+    pub(crate) fn cst(&self) -> &str {
+        match self.cod_cont.as_str() {
+            "01" | "51" => "01",
+            "02" | "52" => "02",
+            "03" | "53" => "03",
+            "31" | "32" => "05",
+            _ => "",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Regime {
+    pub(crate) cod_inc_trib: String,
+    pub(crate) ind_apro_cred: String,
+    pub(crate) cod_tipo_cont: String,
+}
+
+impl Regime {
+    // Whether an M210/M610 line contradicts an exclusive regime of 0110. ST (COD_CONT 31, 32) is
+    // found in both regimes.
+    pub(crate) fn diverge(&self, apuracao: &Apuracao) -> bool {
+        match self.cod_inc_trib.as_str() {
+            "1" => apuracao.cumulativo(),
+            "2" => !apuracao.cumulativo() && apuracao.cst() != "05",
+            _ => false,
+        }
+    }
+}
+
+pub struct Resumo {
+    pub(crate) summary: HashMap<(String, String, String), f64>,
+    // The same ICMS with the registers whose COFINS has its own CST (C870, D205, D300, D350, D605)
+    // split by that CST.
+    pub(crate) summary_cofins: HashMap<(String, String, String), f64>,
+    pub(crate) m210: Vec<Apuracao>,
+    pub(crate) m610: Vec<Apuracao>,
+    pub(crate) f100: Vec<F100Linha>,
+    pub(crate) regime: Regime,
+    pub(crate) monofasicos: Vec<monofasico::Item>,
+    pub(crate) itens: Itens,
+    // ICMS left out of VL_BC_PIS/VL_BC_COFINS of the C170 items, by contribution, CST and rate.
+    pub(crate) excluido: HashMap<(String, String, String), f64>,
+    // Reduction of the purchase credits by the ICMS, by branch, register and CST.
+    pub(crate) reducao: HashMap<(String, String, String), credito::Reducao>,
+    // Debts of the period by DARF revenue code (M205/M605, without the variation).
+    pub(crate) debitos: HashMap<String, f64>,
+    // Contributions payable of the period by DARF revenue code (M200/M600).
+    pub(crate) apurado: HashMap<String, f64>,
 }
 
-fn to_f64(input: &Option<&String>) -> f64 {
-    let replaced = input.unwrap().replace(',', ".");
-    replaced.parse::<f64>().unwrap_or(0_f64)
+// Amounts may be written as "1.234,56" or "1234.56". The EFD files use a decimal comma without
+// thousands separators.
+pub(crate) fn decimal(value: &str) -> Option<f64> {
+    if value.contains(',') {
+        value.replace('.', "").replace(',', ".").parse::<f64>().ok()
+    } else {
+        value.parse::<f64>().ok()
+    }
+}
+
+pub(crate) fn to_f64(input: &Option<&String>) -> f64 {
+    decimal(input.unwrap()).unwrap_or(0_f64)
+}
+
+// An EFD ICMS/IPI total: branch, model and CFOP.
+type Chave = (String, String, String);
+
+// The ICMS of each EFD ICMS/IPI total is shared by every register of EFD Contribuições that draws
+// on it, in proportion to their revenue, once the ICMS of the documents that carry their own
+// (C170, C175) is taken out. A register without a model or CFOP (D350, D600, and F500/F550 when
+// they aren't informed) draws on every model or on every outbound CFOP, in proportion to their
+// ICMS. Inbound CFOPs, such as returns of sales, don't carry ICMS charged on the revenue.
+struct Rateio<'a> {
+    efd_icms_ipi: &'a HashMap<Chave, f64>,
+    direto: HashMap<Chave, f64>,
+    demanda: HashMap<Chave, f64>,
+}
+
+impl<'a> Rateio<'a> {
+    fn new(efd_icms_ipi: &'a HashMap<Chave, f64>, direto: HashMap<Chave, f64>) -> Self {
+        Self {
+            efd_icms_ipi,
+            direto,
+            demanda: HashMap::new(),
+        }
+    }
+
+    // Totals a register draws on, with the share of its revenue that goes to each.
+    fn totais(&self, chave: &Chave) -> Vec<(&'a Chave, f64)> {
+        let efd_icms_ipi: &'a HashMap<Chave, f64> = self.efd_icms_ipi;
+
+        let totais: Vec<(&Chave, f64)> = efd_icms_ipi
+            .iter()
+            .filter(|(k, _)| k.0 == chave.0 && (chave.1.is_empty() || k.1 == chave.1))
+            .filter(|(k, _)| {
+                if chave.2.is_empty() {
+                    k.2.starts_with(['5', '6', '7'])
+                } else {
+                    k.2 == chave.2
+                }
+            })
+            .map(|(k, v)| (k, *v))
+            .collect();
+
+        let vl_icms: f64 = totais.iter().map(|(_, v)| v).sum();
+        if vl_icms <= 0_f64 {
+            return Vec::new();
+        }
+
+        totais.into_iter().map(|(k, v)| (k, v / vl_icms)).collect()
+    }
+
+    fn demandar(&mut self, chave: &Chave, vl_opr: f64) {
+        for (total, parcela) in self.totais(chave) {
+            *self.demanda.entry(total.clone()).or_insert(0_f64) += vl_opr * parcela;
+        }
+    }
+
+    // ICMS of a register, with the revenue it was entered with in `demandar`.
+    fn vl_icms(&self, chave: &Chave, vl_opr: f64) -> f64 {
+        self.totais(chave)
+            .into_iter()
+            .map(|(total, parcela)| {
+                let demanda = *self.demanda.get(total).unwrap_or(&0_f64);
+                let direto = *self.direto.get(total).unwrap_or(&0_f64);
+                let disponivel = (self.efd_icms_ipi[total] - direto).max(0_f64);
+
+                if demanda > 0_f64 {
+                    disponivel * vl_opr * parcela / demanda
+                } else {
+                    0_f64
+                }
+            })
+            .sum()
+    }
+}
+
+// Splits the ICMS of a group across the CSTs of its revenue.
+fn ratear_cst(
+    summary: &mut HashMap<(String, String, String), f64>,
+    cnpj: &str,
+    reg: &str,
+    vl_icms: f64,
+    vl_opr: f64,
+    vl_opr_cst: HashMap<String, f64>,
+) {
+    if vl_opr == 0_f64 {
+        return;
+    }
+
+    for (cst, vl_opr_cst) in vl_opr_cst {
+        let value = summary
+            .entry((cnpj.to_string(), reg.to_string(), cst))
+            .or_insert(0_f64);
+        *value += vl_icms / vl_opr * vl_opr_cst;
+    }
+}
+
+// CNPJs of the establishments, each with whether its operations depend on its EFD ICMS/IPI file:
+// the ones with C010, and the ones whose D010 has transport or communication revenue (D200, D300,
+// D350, D600). Branches with only the F block, such as services, file no EFD ICMS/IPI.
+pub(crate) fn estabelecimentos(path: &PathBuf) -> Vec<(String, bool)> {
+    let file: File = File::open(path).unwrap();
+
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(Some(WINDOWS_1252)) // ISO-8859-1 encoding
+        .build(file);
+
+    ler_estabelecimentos(BufReader::new(decoder))
+}
+
+fn ler_estabelecimentos(reader: impl BufRead) -> Vec<(String, bool)> {
+    let mut cnpjs: Vec<(String, bool)> = Vec::new();
+    let mut atual: Option<usize> = None;
+
+    for line in reader.lines() {
+        let l = line.unwrap();
+
+        let r: Vec<&str> = l.split('|').collect();
+
+        match r.get(1) {
+            Some(&reg @ ("C010" | "D010" | "F010")) => {
+                let cnpj = r.get(2).unwrap().to_string();
+                let index = match cnpjs.iter().position(|(c, _)| *c == cnpj) {
+                    Some(index) => index,
+                    None => {
+                        cnpjs.push((cnpj, false));
+                        cnpjs.len() - 1
+                    }
+                };
+                if reg == "C010" {
+                    cnpjs[index].1 = true;
+                }
+                atual = Some(index);
+            }
+            Some(&("D200" | "D300" | "D350" | "D600")) => {
+                if let Some(index) = atual {
+                    cnpjs[index].1 = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    cnpjs
 }
 
 pub(crate) fn summarize(
     path: PathBuf,
     efd_icms_ipi: HashMap<(String, String, String), f64>,
-) -> (HashMap<String, f64>, Vec<Apuracao>, Vec<Apuracao>) {
-    let mut summary: HashMap<String, f64> = HashMap::new();
+    produtos: HashMap<(String, String), String>,
+    config: &Config,
+) -> Resumo {
+    let mut classificador = Classificador::new(produtos, &config.monofasico);
+    let mut regime: Regime = Regime::default();
+    let mut summary: HashMap<(String, String, String), f64> = HashMap::new();
     let mut m210: Vec<Apuracao> = Vec::new();
     let mut m610: Vec<Apuracao> = Vec::new();
+    let mut f100: Vec<F100Linha> = Vec::new();
+    let mut itens: Itens = HashMap::new();
+    let mut excluido: HashMap<(String, String, String), f64> = HashMap::new();
+
+    // M215/M615 reductions count as an ICMS exclusion when their description mentions the ICMS
+    // or their code is the one used for the adjustments of this program.
+    let cod_aj_bc: &str = config
+        .ajuste
+        .as_ref()
+        .map_or("41", |ajuste| ajuste.cod_aj_bc.as_str());
+    let mut debitos: HashMap<String, f64> = HashMap::new();
+    let mut apurado: HashMap<String, f64> = HashMap::new();
+    let mut m210_incluido: bool = false;
+    let mut m610_incluido: bool = false;
 
     println!("{}", &path.display());
 
@@ -51,29 +401,77 @@ pub(crate) fn summarize(
 
     let reader = BufReader::new(decoder);
 
+    let mut cnpj_0140: String = String::new();
     let mut c010_cnpj: String = String::new();
     let mut c010_ind_escri: String = String::new();
 
     let mut c100_ind_oper: String = String::new();
+    let mut compras: Compras = Compras::default();
     let mut c100_cod_mod: String = String::new();
     let mut c100_chv_nfe: String = String::new();
     let mut c100_vl_icms: f64 = f64::from(0);
 
-    let mut c175_cache: HashMap<String, C175Value> = HashMap::new();
+    let mut c175_cache: HashMap<(String, String), C175Value> = HashMap::new();
+
+    // ICMS of the EFD ICMS/IPI totals already taken by C170 and C175, which carry their own.
+    let mut direto: HashMap<(String, String, String), f64> = HashMap::new();
 
     let mut c180_cod_mod: String = String::new();
+    let mut c180_cod_item: String = String::new();
     let mut c180_cache: HashMap<(String, String, String), C180Value> = HashMap::new();
 
     let mut c400_cod_mod: String = String::new();
-    let mut c400_cache: HashMap<(String, String), C400Value> = HashMap::new();
+    let mut c400_cache: HashMap<(String, String, String), C400Value> = HashMap::new();
 
-    for line in reader.lines() {
+    let mut c860_cod_mod: String = String::new();
+    let mut c870_cache: HashMap<(String, String, String), C870Value> = HashMap::new();
+
+    let mut d200_cod_mod: String = String::new();
+    let mut d200_cfop: String = String::new();
+    let mut d200_cache: HashMap<(String, String, String), D200Value> = HashMap::new();
+
+    let mut d300_cache: HashMap<(String, String, String), D300Value> = HashMap::new();
+
+    let mut d350_cache: HashMap<(String, String, String), D350Value> = HashMap::new();
+
+    let mut d600_cod_mod: String = String::new();
+    let mut d600_cache: HashMap<(String, String, String), D600Value> = HashMap::new();
+
+    let mut f100_cache: HashMap<(String, String, String), f64> = HashMap::new();
+    let mut f100_pending: Vec<(usize, (String, String, String))> = Vec::new();
+
+    let mut f500_cache: HashMap<(String, String, String), F500Value> = HashMap::new();
+
+    let mut f550_cache: HashMap<(String, String, String), F500Value> = HashMap::new();
+
+    // ICMS of the registers with a COFINS CST of their own, by branch, register and that CST.
+    let mut cofins: HashMap<(String, String, String), f64> = HashMap::new();
+
+    for (numero, line) in reader.lines().enumerate() {
         let l = line.unwrap();
 
         let r: Vec<String> = l.split('|').map(|s| s.to_string()).collect();
 
         if let Some(reg) = r.get(1) {
             match reg.as_str() {
+                "0110" => {
+                    regime = Regime {
+                        cod_inc_trib: r.get(2).unwrap().to_string(),
+                        ind_apro_cred: r.get(3).unwrap().to_string(),
+                        cod_tipo_cont: r.get(4).unwrap().to_string(),
+                    }
+                }
+
+                // 0200 follows the 0140 of its establishment.
+                "0140" => cnpj_0140 = r.get(4).unwrap().to_string(),
+
+                "0200" => {
+                    let cod_item: String = r.get(2).unwrap().to_string();
+                    let cod_ncm: String = r.get(8).unwrap().to_string();
+
+                    classificador.add_produto(cnpj_0140.clone(), cod_item, cod_ncm);
+                }
+
                 "C010" => {
                     c010_cnpj = r.get(2).unwrap().to_string();
                     c010_ind_escri = r.get(3).unwrap().to_string();
@@ -87,60 +485,156 @@ pub(crate) fn summarize(
                 }
 
                 "C170" => {
+                    if c100_ind_oper == "0" {
+                        compras.c170(&c010_cnpj, &r);
+                    }
                     if c100_ind_oper != "1" {
                         continue;
                     }
 
+                    let cst: &str = classificador.cst(
+                        reg,
+                        &c010_cnpj,
+                        r.get(3).unwrap(),
+                        r.get(25).unwrap(),
+                        to_f64(&r.get(7)),
+                    );
+
+                    if c100_cod_mod != "55" || c010_ind_escri != "1" {
+                        let key = (
+                            c010_cnpj.clone(),
+                            c100_cod_mod.clone(),
+                            r.get(11).unwrap().clone(),
+                        );
+                        *direto.entry(key).or_insert(0_f64) += to_f64(&r.get(15));
+                    }
+
                     if (c100_cod_mod != "55" || c010_ind_escri != "1")
-                        && *r.get(25).unwrap() == "01"
+                        && CST_TRIBUTADO.contains(&cst)
                     {
                         let vl_icms = to_f64(&r.get(15));
 
-                        let value = summary.entry(reg.to_string()).or_insert(0_f64);
+                        let key = (c010_cnpj.clone(), reg.to_string(), cst.to_string());
+                        let value = summary.entry(key).or_insert(0_f64);
                         *value += vl_icms;
+
+                        for (tributo, aliq) in
+                            [("PIS", to_f64(&r.get(27))), ("COFINS", to_f64(&r.get(33)))]
+                        {
+                            let key = (
+                                c010_cnpj.clone(),
+                                tributo.to_string(),
+                                cst.to_string(),
+                                aliquota(aliq),
+                            );
+                            *itens.entry(key).or_insert(0_f64) += vl_icms;
+                        }
+
+                        // A base below VL_ITEM less VL_DESC means the ICMS, up to its value, was
+                        // already left out.
+                        let vl_item = to_f64(&r.get(7)) - to_f64(&r.get(8));
+                        for (tributo, vl_bc, aliq) in [
+                            ("PIS", to_f64(&r.get(26)), to_f64(&r.get(27))),
+                            ("COFINS", to_f64(&r.get(32)), to_f64(&r.get(33))),
+                        ] {
+                            let vl_excluido = (vl_item - vl_bc).clamp(0_f64, vl_icms);
+                            if vl_excluido > 0_f64 {
+                                let key = (tributo.to_string(), cst.to_string(), aliquota(aliq));
+                                *excluido.entry(key).or_insert(0_f64) += vl_excluido;
+                            }
+                        }
                     }
                 }
 
                 "C175" => {
                     if *r.get(2).unwrap() == "5102" {
-                        let value = c175_cache.entry(c100_chv_nfe.clone()).or_insert(C175Value {
+                        let key = (c010_cnpj.clone(), c100_chv_nfe.clone());
+                        let value = c175_cache.entry(key).or_insert(C175Value {
+                            cod_mod: c100_cod_mod.clone(),
                             vl_icms: c100_vl_icms,
                             vl_opr_cfop5102: 0_f64,
-                            vl_opr_cfop5102_cst01: 0_f64,
+                            vl_opr_cfop5102_cst: HashMap::new(),
                         });
 
                         let vl_opr: f64 = to_f64(&r.get(3));
 
                         value.vl_opr_cfop5102 += vl_opr;
 
-                        if *r.get(5).unwrap() == "01" {
-                            value.vl_opr_cfop5102_cst01 += vl_opr;
+                        let cst: &str = r.get(5).unwrap();
+                        if CST_TRIBUTADO.contains(&cst) {
+                            *value
+                                .vl_opr_cfop5102_cst
+                                .entry(cst.to_string())
+                                .or_insert(0_f64) += vl_opr;
                         }
                     }
                 }
 
-                "C180" => c180_cod_mod = r.get(2).unwrap().clone(),
+                "C191" | "C195" => compras.c191(reg, &c010_cnpj, &r),
+
+                "C180" => {
+                    c180_cod_mod = r.get(2).unwrap().clone();
+                    c180_cod_item = r.get(5).unwrap().clone();
+
+                    // C180 informs the NCM of its item, which may be missing from 0200.
+                    let cod_ncm: &String = r.get(6).unwrap();
+                    if !cod_ncm.is_empty() {
+                        classificador.add_produto(
+                            c010_cnpj.clone(),
+                            c180_cod_item.clone(),
+                            cod_ncm.clone(),
+                        );
+                    }
+                }
 
+                // Each CFOP of the consolidation draws on the EFD ICMS/IPI total of that CFOP.
                 "C181" => {
                     if c010_ind_escri != "2" || c180_cod_mod == "65" {
-                        if *r.get(3).unwrap() == "5102" {
-                            let vl_opr: f64 = to_f64(&r.get(4));
-                            let cfop: String = r.get(3).unwrap().to_string();
+                        let vl_opr: f64 = to_f64(&r.get(4));
+                        let cfop: String = r.get(3).unwrap().to_string();
 
-                            let key = (c010_cnpj.clone(), c180_cod_mod.clone(), cfop);
-                            let value = c180_cache.entry(key).or_insert(C180Value {
-                                vl_opr_cfop5102: 0_f64,
-                                vl_opr_cfop5102_cst01: 0_f64,
-                            });
+                        let key = (c010_cnpj.clone(), c180_cod_mod.clone(), cfop);
+                        let value = c180_cache.entry(key).or_insert(C180Value {
+                            vl_opr: 0_f64,
+                            vl_opr_cst: HashMap::new(),
+                            pis: Receita::default(),
+                            cofins: Receita::default(),
+                        });
 
-                            value.vl_opr_cfop5102 += vl_opr;
+                        value.vl_opr += vl_opr;
 
-                            if *r.get(2).unwrap() == "01" {
-                                value.vl_opr_cfop5102_cst01 += vl_opr;
-                            }
-                        } else {
-                            todo!("Invalid {}", r.get(3).unwrap());
+                        let cst: &str = classificador.cst(
+                            "C180",
+                            &c010_cnpj,
+                            &c180_cod_item,
+                            r.get(2).unwrap(),
+                            vl_opr,
+                        );
+                        if CST_TRIBUTADO.contains(&cst) {
+                            *value.vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
                         }
+                        value.pis.add(cst, to_f64(&r.get(7)), vl_opr);
+                    }
+                }
+
+                "C185" => {
+                    if c010_ind_escri != "2" || c180_cod_mod == "65" {
+                        let cfop: String = r.get(3).unwrap().to_string();
+
+                        let key = (c010_cnpj.clone(), c180_cod_mod.clone(), cfop);
+                        let value = c180_cache.entry(key).or_insert(C180Value {
+                            vl_opr: 0_f64,
+                            vl_opr_cst: HashMap::new(),
+                            pis: Receita::default(),
+                            cofins: Receita::default(),
+                        });
+
+                        let cst: &str = classificador.reclassificar(
+                            &c010_cnpj,
+                            &c180_cod_item,
+                            r.get(2).unwrap(),
+                        );
+                        value.cofins.add(cst, to_f64(&r.get(7)), to_f64(&r.get(4)));
                     }
                 }
 
@@ -150,105 +644,767 @@ pub(crate) fn summarize(
                     if c010_ind_escri != "1" {
                         let vl_opr: f64 = to_f64(&r.get(3));
 
-                        let key = (c010_cnpj.clone(), c400_cod_mod.clone());
+                        // The ECF sales of C400 are matched to CFOP 5102.
+                        let key = (c010_cnpj.clone(), c400_cod_mod.clone(), "5102".to_string());
                         let value = c400_cache.entry(key).or_insert(C400Value {
                             vl_opr: 0_f64,
-                            vl_opr_cst01: 0_f64,
+                            vl_opr_cst: HashMap::new(),
+                            pis: Receita::default(),
+                            cofins: Receita::default(),
                         });
 
                         value.vl_opr += vl_opr;
 
-                        if *r.get(2).unwrap() == "01" {
-                            value.vl_opr_cst01 += vl_opr;
+                        let cst: &str = classificador.cst(
+                            reg,
+                            &c010_cnpj,
+                            r.get(9).unwrap(),
+                            r.get(2).unwrap(),
+                            vl_opr,
+                        );
+                        if CST_TRIBUTADO.contains(&cst) {
+                            *value.vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
                         }
+                        value.pis.add(cst, to_f64(&r.get(5)), vl_opr);
+                    }
+                }
+
+                "C485" => {
+                    if c010_ind_escri != "1" {
+                        let key = (c010_cnpj.clone(), c400_cod_mod.clone(), "5102".to_string());
+                        let value = c400_cache.entry(key).or_insert(C400Value {
+                            vl_opr: 0_f64,
+                            vl_opr_cst: HashMap::new(),
+                            pis: Receita::default(),
+                            cofins: Receita::default(),
+                        });
+
+                        let cst: &str = classificador.reclassificar(
+                            &c010_cnpj,
+                            r.get(9).unwrap(),
+                            r.get(2).unwrap(),
+                        );
+                        value.cofins.add(cst, to_f64(&r.get(5)), to_f64(&r.get(3)));
                     }
                 }
 
+                "C860" => c860_cod_mod = r.get(2).unwrap().clone(),
+
+                "C870" => {
+                    let vl_opr: f64 = to_f64(&r.get(4));
+                    let cfop: String = r.get(3).unwrap().to_string();
+
+                    let key = (c010_cnpj.clone(), c860_cod_mod.clone(), cfop);
+                    let value = c870_cache.entry(key).or_insert(C870Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        vl_opr_cst_cofins: HashMap::new(),
+                    });
+
+                    value.vl_opr += vl_opr;
+
+                    let cst: &str = classificador.cst(
+                        reg,
+                        &c010_cnpj,
+                        r.get(2).unwrap(),
+                        r.get(6).unwrap(),
+                        vl_opr,
+                    );
+                    if CST_TRIBUTADO.contains(&cst) {
+                        *value.vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                    }
+
+                    let cst_cofins: &str = classificador.reclassificar(
+                        &c010_cnpj,
+                        r.get(2).unwrap(),
+                        r.get(10).unwrap(),
+                    );
+                    if CST_TRIBUTADO.contains(&cst_cofins) {
+                        *value
+                            .vl_opr_cst_cofins
+                            .entry(cst_cofins.to_string())
+                            .or_insert(0_f64) += vl_opr;
+                    }
+                }
+
+                "D010" => c010_cnpj = r.get(2).unwrap().to_string(),
+
+                "D200" => {
+                    d200_cod_mod = r.get(2).unwrap().to_string();
+                    d200_cfop = r.get(8).unwrap().to_string();
+                }
+
+                "D201" | "D205" => {
+                    let vl_opr: f64 = to_f64(&r.get(3));
+
+                    let key = (c010_cnpj.clone(), d200_cod_mod.clone(), d200_cfop.clone());
+                    let value = d200_cache.entry(key).or_insert(D200Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        vl_opr_cofins: 0_f64,
+                        vl_opr_cst_cofins: HashMap::new(),
+                    });
+
+                    let (vl_opr_total, vl_opr_cst) = if reg == "D201" {
+                        (&mut value.vl_opr, &mut value.vl_opr_cst)
+                    } else {
+                        (&mut value.vl_opr_cofins, &mut value.vl_opr_cst_cofins)
+                    };
+
+                    *vl_opr_total += vl_opr;
+
+                    let cst: &str = r.get(2).unwrap();
+                    if CST_TRIBUTADO.contains(&cst) {
+                        *vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                    }
+                }
+
+                "D300" => {
+                    let vl_opr: f64 = to_f64(&r.get(9));
+                    let cod_mod: String = r.get(2).unwrap().to_string();
+                    let cfop: String = r.get(7).unwrap().to_string();
+
+                    let key = (c010_cnpj.clone(), cod_mod, cfop);
+                    let value = d300_cache.entry(key).or_insert(D300Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        vl_opr_cst_cofins: HashMap::new(),
+                    });
+
+                    value.vl_opr += vl_opr;
+
+                    // CST_PIS and CST_COFINS
+                    for (index, vl_opr_cst) in [
+                        (11, &mut value.vl_opr_cst),
+                        (15, &mut value.vl_opr_cst_cofins),
+                    ] {
+                        let cst: &str = r.get(index).unwrap();
+                        if CST_TRIBUTADO.contains(&cst) {
+                            *vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                        }
+                    }
+                }
+
+                "D350" => {
+                    let vl_opr: f64 = to_f64(&r.get(10));
+                    let cod_mod: String = r.get(2).unwrap().to_string();
+
+                    // Without CFOP, drawing on every outbound CFOP of the model.
+                    let key = (c010_cnpj.clone(), cod_mod, String::new());
+                    let value = d350_cache.entry(key).or_insert(D350Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        vl_opr_cst_cofins: HashMap::new(),
+                    });
+
+                    value.vl_opr += vl_opr;
+
+                    // CST_PIS and CST_COFINS
+                    for (index, vl_opr_cst) in [
+                        (11, &mut value.vl_opr_cst),
+                        (17, &mut value.vl_opr_cst_cofins),
+                    ] {
+                        let cst: &str = r.get(index).unwrap();
+                        if CST_TRIBUTADO.contains(&cst) {
+                            *vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                        }
+                    }
+                }
+
+                "D600" => d600_cod_mod = r.get(2).unwrap().to_string(),
+
+                "D601" | "D605" => {
+                    let vl_opr: f64 = to_f64(&r.get(3));
+
+                    let key = (c010_cnpj.clone(), d600_cod_mod.clone(), String::new());
+                    let value = d600_cache.entry(key).or_insert(D600Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        vl_opr_cofins: 0_f64,
+                        vl_opr_cst_cofins: HashMap::new(),
+                    });
+
+                    let (vl_opr_total, vl_opr_cst) = if reg == "D601" {
+                        (&mut value.vl_opr, &mut value.vl_opr_cst)
+                    } else {
+                        (&mut value.vl_opr_cofins, &mut value.vl_opr_cst_cofins)
+                    };
+
+                    *vl_opr_total += vl_opr;
+
+                    let cst: &str = r.get(5).unwrap();
+                    if CST_TRIBUTADO.contains(&cst) {
+                        *vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                    }
+                }
+
+                "F010" => c010_cnpj = r.get(2).unwrap().to_string(),
+
                 "F100" => {
-                    // There is no documentation about how to implement that.
+                    // Only revenue lines can carry ICMS, IND_OPER 0 are purchases.
+                    if *r.get(2).unwrap() == "0" {
+                        continue;
+                    }
+
+                    let ind_oper: String = r.get(2).unwrap().to_string();
+                    let vl_oper: f64 = to_f64(&r.get(6));
+                    let nat_bc_cred: String = r.get(15).unwrap().to_string();
+                    let cod_cta: String = r.get(17).unwrap().to_string();
+
+                    let icms: Option<F100Icms> = config
+                        .f100
+                        .iter()
+                        .find(|rule| {
+                            rule.matches(&nat_bc_cred, &ind_oper, r.get(7).unwrap(), &cod_cta)
+                        })
+                        .and_then(|rule| rule.icms.clone());
+
+                    let cst: String = classificador
+                        .cst(
+                            reg,
+                            &c010_cnpj,
+                            r.get(4).unwrap(),
+                            r.get(7).unwrap(),
+                            vl_oper,
+                        )
+                        .to_string();
+
+                    let vl_icms: Option<f64> = match icms {
+                        Some(F100Icms::Aliquota(aliq_icms))
+                            if CST_TRIBUTADO.contains(&cst.as_str()) =>
+                        {
+                            Some(vl_oper * aliq_icms / 100_f64)
+                        }
+                        Some(F100Icms::EfdIcmsIpi { cod_mod, cfop }) => {
+                            let key = (c010_cnpj.clone(), cod_mod, cfop);
+                            *f100_cache.entry(key.clone()).or_insert(0_f64) += vl_oper;
+
+                            if CST_TRIBUTADO.contains(&cst.as_str()) {
+                                f100_pending.push((f100.len(), key));
+                            }
+                            None
+                        }
+                        _ => None,
+                    };
+
+                    f100.push(F100Linha {
+                        cnpj: c010_cnpj.clone(),
+                        cst,
+                        cod_cta,
+                        vl_oper,
+                        vl_icms,
+                        aliq_pis: to_f64(&r.get(9)),
+                        aliq_cofins: to_f64(&r.get(13)),
+                    });
+                }
+
+                "F500" => {
+                    let vl_opr: f64 = to_f64(&r.get(2));
+                    let cod_mod: String = r.get(13).unwrap().to_string();
+                    let cfop: String = r.get(14).unwrap().to_string();
+
+                    let key = (c010_cnpj.clone(), cod_mod, cfop);
+                    let value = f500_cache.entry(key).or_insert(F500Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        pis: Receita::default(),
+                        cofins: Receita::default(),
+                    });
+
+                    value.vl_opr += vl_opr;
+
+                    let cst: &str = r.get(3).unwrap();
+                    if CST_TRIBUTADO.contains(&cst) {
+                        *value.vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                    }
+                    value.pis.add(cst, to_f64(&r.get(6)), vl_opr);
+                    value
+                        .cofins
+                        .add(r.get(8).unwrap(), to_f64(&r.get(11)), vl_opr);
+                }
+
+                "F550" => {
+                    let vl_opr: f64 = to_f64(&r.get(2));
+                    let cod_mod: String = r.get(13).unwrap().to_string();
+                    let cfop: String = r.get(14).unwrap().to_string();
+
+                    let key = (c010_cnpj.clone(), cod_mod, cfop);
+                    let value = f550_cache.entry(key).or_insert(F500Value {
+                        vl_opr: 0_f64,
+                        vl_opr_cst: HashMap::new(),
+                        pis: Receita::default(),
+                        cofins: Receita::default(),
+                    });
+
+                    value.vl_opr += vl_opr;
+
+                    let cst: &str = r.get(3).unwrap();
+                    if CST_TRIBUTADO.contains(&cst) {
+                        *value.vl_opr_cst.entry(cst.to_string()).or_insert(0_f64) += vl_opr;
+                    }
+                    value.pis.add(cst, to_f64(&r.get(6)), vl_opr);
+                    value
+                        .cofins
+                        .add(r.get(8).unwrap(), to_f64(&r.get(11)), vl_opr);
                 }
 
-                "C381" | "C385" | "C491" | "C495" | "C601" | "C605" | "C870" | "D201" | "D205"
-                | "D300" | "D350" | "D601" | "D605" | "F500" | "F550" => {
+                "C381" | "C385" | "C491" | "C495" | "C601" | "C605" => {
                     todo!("Registro {} não implantado", reg)
                 }
 
                 "M210" => {
-                    if *r.get(2).unwrap() == "01" || *r.get(2).unwrap() == "51" {
+                    m210_incluido = matches!(
+                        r.get(2).unwrap().as_str(),
+                        "01" | "02" | "31" | "32" | "51" | "52"
+                    );
+                    if m210_incluido {
                         if r.len() == 15 {
                             m210.push(Apuracao {
+                                cod_cont: r.get(2).unwrap().to_string(),
                                 vl_rec_brt: to_f64(&r.get(3)),
                                 vl_bc_cont: to_f64(&r.get(4)),
                                 aliq_cont: to_f64(&r.get(5)),
+                                linha: l.clone(),
+                                numero,
+                                vl_ajus_icms: 0_f64,
                             })
                         } else {
                             m210.push(Apuracao {
+                                cod_cont: r.get(2).unwrap().to_string(),
                                 vl_rec_brt: to_f64(&r.get(3)),
                                 vl_bc_cont: to_f64(&r.get(7)),
                                 aliq_cont: to_f64(&r.get(8)),
+                                linha: l.clone(),
+                                numero,
+                                vl_ajus_icms: 0_f64,
                             })
                         }
                     }
                 }
 
                 "M610" => {
-                    if *r.get(2).unwrap() == "01" || *r.get(2).unwrap() == "51" {
+                    m610_incluido = matches!(
+                        r.get(2).unwrap().as_str(),
+                        "01" | "02" | "31" | "32" | "51" | "52"
+                    );
+                    if m610_incluido {
                         if r.len() == 15 {
                             m610.push(Apuracao {
+                                cod_cont: r.get(2).unwrap().to_string(),
                                 vl_rec_brt: to_f64(&r.get(3)),
                                 vl_bc_cont: to_f64(&r.get(4)),
                                 aliq_cont: to_f64(&r.get(5)),
+                                linha: l.clone(),
+                                numero,
+                                vl_ajus_icms: 0_f64,
                             })
                         } else {
                             m610.push(Apuracao {
+                                cod_cont: r.get(2).unwrap().to_string(),
                                 vl_rec_brt: to_f64(&r.get(3)),
                                 vl_bc_cont: to_f64(&r.get(7)),
                                 aliq_cont: to_f64(&r.get(8)),
+                                linha: l.clone(),
+                                numero,
+                                vl_ajus_icms: 0_f64,
                             })
                         }
                     }
                 }
 
+                "M200" | "M600" => {
+                    let tributo = if reg == "M200" { "PIS" } else { "COFINS" };
+
+                    // VL_CONT_NC_REC and VL_CONT_CUM_REC
+                    for (cumulativo, index) in [(false, 8), (true, 12)] {
+                        let codigo = perdcomp::codigo(tributo, cumulativo).to_string();
+                        *apurado.entry(codigo).or_insert(0_f64) += to_f64(&r.get(index));
+                    }
+                }
+
+                "M205" | "M605" => {
+                    let cod_rec: &str = r.get(3).unwrap();
+                    let codigo: String = cod_rec.get(..4).unwrap_or(cod_rec).to_string();
+
+                    *debitos.entry(codigo).or_insert(0_f64) += to_f64(&r.get(4));
+                }
+
+                "M215" | "M615" => {
+                    let (incluido, apuracoes) = if reg == "M215" {
+                        (m210_incluido, &mut m210)
+                    } else {
+                        (m610_incluido, &mut m610)
+                    };
+
+                    let icms = r.get(6).unwrap().to_uppercase().contains("ICMS")
+                        || r.get(4).unwrap() == cod_aj_bc;
+
+                    if incluido && icms && r.get(2).unwrap() == "0" {
+                        if let Some(apuracao) = apuracoes.last_mut() {
+                            apuracao.vl_ajus_icms += to_f64(&r.get(3));
+                        }
+                    }
+                }
+
                 _ => {}
             }
         }
     }
 
-    for (_key, value) in c175_cache.iter() {
-        let vl_icms: f64 = value.vl_icms / value.vl_opr_cfop5102 * value.vl_opr_cfop5102_cst01;
-        let value = summary.entry("C175".to_string()).or_insert(0_f64);
-        *value += vl_icms;
+    for (key, value) in c175_cache {
+        *direto
+            .entry((key.0.clone(), value.cod_mod.clone(), "5102".to_string()))
+            .or_insert(0_f64) += value.vl_icms;
+
+        for (cst, vl_opr_cst) in value.vl_opr_cfop5102_cst {
+            let vl_icms: f64 = value.vl_icms / value.vl_opr_cfop5102 * vl_opr_cst;
+            let value = summary
+                .entry((key.0.clone(), "C175".to_string(), cst))
+                .or_insert(0_f64);
+            *value += vl_icms;
+        }
     }
 
-    if c180_cache.is_empty() {
-    } else {
-        for (key, value) in c180_cache {
-            let vl_icms: f64 = *efd_icms_ipi.get(&key.clone())
-                .expect("Não foram apresentados os respectivos arquivos EFD ICMS/IPI.");
+    // Every register drawing on the EFD ICMS/IPI totals is known before they are shared.
+    let mut rateio = Rateio::new(&efd_icms_ipi, direto);
+    let demandas = c180_cache
+        .iter()
+        .map(|(key, value)| (key, value.vl_opr))
+        .chain(c400_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(c870_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(d200_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(d300_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(d350_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(d600_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(f500_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(f550_cache.iter().map(|(key, value)| (key, value.vl_opr)))
+        .chain(f100_cache.iter().map(|(key, vl_oper)| (key, *vl_oper)));
+    for (key, vl_opr) in demandas {
+        rateio.demandar(key, vl_opr);
+    }
 
-            let vl_icms_prop: f64 = vl_icms / value.vl_opr_cfop5102 * value.vl_opr_cfop5102_cst01;
+    for (key, value) in c180_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
 
-            let value = summary.entry("C180".to_string()).or_insert(0_f64);
-            *value += vl_icms_prop;
-        }
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "C180",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+
+        value.pis.ratear(&key.0, "PIS", vl_icms, &mut itens);
+        value.cofins.ratear(&key.0, "COFINS", vl_icms, &mut itens);
     }
 
-    if c400_cache.is_empty() {
-    } else {
-        for (key, value) in c400_cache {
-            let cnpj = key.0;
-            let cod_mod = key.1;
+    for (key, value) in c400_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "C400",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+
+        value.pis.ratear(&key.0, "PIS", vl_icms, &mut itens);
+        value.cofins.ratear(&key.0, "COFINS", vl_icms, &mut itens);
+    }
+
+    for (key, value) in c870_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "C870",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+        ratear_cst(
+            &mut cofins,
+            &key.0,
+            "C870",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst_cofins,
+        );
+    }
+
+    for (key, value) in d200_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        // Without D205, COFINS follows the CSTs of D201.
+        let (vl_opr_cofins, vl_opr_cst_cofins) = if value.vl_opr_cofins > 0_f64 {
+            (value.vl_opr_cofins, value.vl_opr_cst_cofins)
+        } else {
+            (value.vl_opr, value.vl_opr_cst.clone())
+        };
+
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "D200",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+        ratear_cst(
+            &mut cofins,
+            &key.0,
+            "D200",
+            vl_icms,
+            vl_opr_cofins,
+            vl_opr_cst_cofins,
+        );
+    }
+
+    for (key, value) in d300_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "D300",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+        ratear_cst(
+            &mut cofins,
+            &key.0,
+            "D300",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst_cofins,
+        );
+    }
+
+    for (key, value) in d350_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "D350",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+        ratear_cst(
+            &mut cofins,
+            &key.0,
+            "D350",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst_cofins,
+        );
+    }
+
+    for (key, value) in d600_cache {
+        let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+        // Without D605, COFINS follows the CSTs of D601.
+        let (vl_opr_cofins, vl_opr_cst_cofins) = if value.vl_opr_cofins > 0_f64 {
+            (value.vl_opr_cofins, value.vl_opr_cst_cofins)
+        } else {
+            (value.vl_opr, value.vl_opr_cst.clone())
+        };
 
-            let vl_icms: f64 = *efd_icms_ipi
-                .get(&(cnpj, cod_mod, "5102".to_string()))
-                .expect("Não foram apresentados os respectivos arquivos EFD ICMS/IPI.");
+        ratear_cst(
+            &mut summary,
+            &key.0,
+            "D600",
+            vl_icms,
+            value.vl_opr,
+            value.vl_opr_cst,
+        );
+        ratear_cst(
+            &mut cofins,
+            &key.0,
+            "D600",
+            vl_icms,
+            vl_opr_cofins,
+            vl_opr_cst_cofins,
+        );
+    }
+
+    // F500 is booked when the revenue is received, while the ICMS is the one of the invoices
+    // issued in the period, so the cash basis result is an approximation. Without COD_MOD or
+    // CFOP, the line draws on every model or outbound CFOP of the branch.
+    for (reg, cache) in [("F500", f500_cache), ("F550", f550_cache)] {
+        for (key, value) in cache {
+            let vl_icms: f64 = rateio.vl_icms(&key, value.vl_opr);
+
+            ratear_cst(
+                &mut summary,
+                &key.0,
+                reg,
+                vl_icms,
+                value.vl_opr,
+                value.vl_opr_cst,
+            );
+
+            value.pis.ratear(&key.0, "PIS", vl_icms, &mut itens);
+            value.cofins.ratear(&key.0, "COFINS", vl_icms, &mut itens);
+        }
+    }
+
+    // F100 lines pointed at an EFD ICMS/IPI total draw on it like the other registers.
+    for (index, key) in f100_pending {
+        let linha = &mut f100[index];
+        linha.vl_icms = Some(rateio.vl_icms(&key, linha.vl_oper));
+    }
 
-            let vl_icms_prop: f64 = vl_icms / value.vl_opr * value.vl_opr_cst01;
+    for linha in f100.iter() {
+        if let Some(vl_icms) = linha.vl_icms {
+            let key = (linha.cnpj.clone(), "F100".to_string(), linha.cst.clone());
+            let value = summary.entry(key).or_insert(0_f64);
+            *value += vl_icms;
 
-            let value = summary.entry("C400".to_string()).or_insert(0_f64);
-            *value += vl_icms_prop;
+            for (tributo, aliq) in [("PIS", linha.aliq_pis), ("COFINS", linha.aliq_cofins)] {
+                let key = (
+                    linha.cnpj.clone(),
+                    tributo.to_string(),
+                    linha.cst.clone(),
+                    aliquota(aliq),
+                );
+                *itens.entry(key).or_insert(0_f64) += vl_icms;
+            }
         }
     }
 
-    (summary, m210, m610)
+    let mut summary_cofins = summary.clone();
+    summary_cofins
+        .retain(|key, _| !matches!(key.1.as_str(), "C870" | "D200" | "D300" | "D350" | "D600"));
+    for (key, value) in cofins {
+        *summary_cofins.entry(key).or_insert(0_f64) += value;
+    }
+
+    Resumo {
+        summary,
+        summary_cofins,
+        m210,
+        m610,
+        f100,
+        regime,
+        monofasicos: classificador.itens(),
+        itens,
+        excluido,
+        reducao: compras.reducoes(&efd_icms_ipi),
+        debitos,
+        apurado,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chave(cnpj: &str, cod_mod: &str, cfop: &str) -> Chave {
+        (cnpj.to_string(), cod_mod.to_string(), cfop.to_string())
+    }
+
+    fn perto(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn rateio_divide_o_icms_disponivel_pela_receita() {
+        let total = chave("11111111000191", "65", "5102");
+        let efd_icms_ipi = HashMap::from([(total.clone(), 1000_f64)]);
+        // 200 of the total were taken by C170 items.
+        let direto = HashMap::from([(total.clone(), 200_f64)]);
+
+        let c180 = total.clone();
+        let f100 = total.clone();
+
+        let mut rateio = Rateio::new(&efd_icms_ipi, direto);
+        rateio.demandar(&c180, 3000_f64);
+        rateio.demandar(&f100, 1000_f64);
+
+        let vl_c180 = rateio.vl_icms(&c180, 3000_f64);
+        let vl_f100 = rateio.vl_icms(&f100, 1000_f64);
+
+        assert!(perto(vl_c180, 600_f64));
+        assert!(perto(vl_f100, 200_f64));
+        assert!(perto(vl_c180 + vl_f100, 800_f64));
+    }
+
+    #[test]
+    fn rateio_sem_cfop_usa_os_cfops_de_saida() {
+        let efd_icms_ipi = HashMap::from([
+            (chave("11111111000191", "55", "5102"), 300_f64),
+            (chave("11111111000191", "55", "6102"), 100_f64),
+            // Returns of sales don't carry ICMS on the revenue.
+            (chave("11111111000191", "55", "1202"), 50_f64),
+        ]);
+        let f500 = chave("11111111000191", "55", "");
+
+        let mut rateio = Rateio::new(&efd_icms_ipi, HashMap::new());
+        rateio.demandar(&f500, 1000_f64);
+
+        assert!(perto(rateio.vl_icms(&f500, 1000_f64), 400_f64));
+    }
+
+    #[test]
+    fn rateio_sem_estabelecimento_ou_sem_icms_e_zero() {
+        let efd_icms_ipi = HashMap::from([
+            (chave("11111111000191", "55", "5102"), 0_f64),
+            (chave("11111111000272", "55", "5102"), 100_f64),
+        ]);
+        let sem_icms = chave("11111111000191", "55", "5102");
+        let sem_arquivo = chave("11111111000353", "55", "5102");
+
+        let mut rateio = Rateio::new(&efd_icms_ipi, HashMap::new());
+        rateio.demandar(&sem_icms, 1000_f64);
+        rateio.demandar(&sem_arquivo, 1000_f64);
+
+        assert_eq!(rateio.vl_icms(&sem_icms, 1000_f64), 0_f64);
+        assert_eq!(rateio.vl_icms(&sem_arquivo, 1000_f64), 0_f64);
+        // Without demand, a total isn't divided by zero.
+        let outra = chave("11111111000272", "55", "5102");
+        assert_eq!(rateio.vl_icms(&outra, 0_f64), 0_f64);
+    }
+
+    #[test]
+    fn estabelecimentos_so_de_servicos_nao_dependem_da_efd_icms_ipi() {
+        let texto = "|C001|0|\n\
+                     |C010|11111111000191|2|\n\
+                     |D001|0|\n\
+                     |D010|11111111000272|\n\
+                     |D200|57|00|1||1|1|5353|01012023|31012023|1000,00|0|\n\
+                     |D010|11111111000353|\n\
+                     |D100|0|0||57|00|1||1|||||||||||\n\
+                     |F001|0|\n\
+                     |F010|11111111000191|\n\
+                     |F010|11111111000434|\n\
+                     |F100|1||||01|1000,00|\n";
+
+        let cnpjs = ler_estabelecimentos(texto.as_bytes());
+
+        assert_eq!(
+            cnpjs,
+            [
+                ("11111111000191".to_string(), true),
+                ("11111111000272".to_string(), true),
+                ("11111111000353".to_string(), false),
+                ("11111111000434".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn decimal_aceita_os_dois_formatos() {
+        assert_eq!(decimal("1.234,56"), Some(1234.56));
+        assert_eq!(decimal("1234,56"), Some(1234.56));
+        assert_eq!(decimal("1234.56"), Some(1234.56));
+        assert_eq!(decimal("Valor"), None);
+        assert_eq!(decimal(""), None);
+    }
 }
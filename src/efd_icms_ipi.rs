@@ -1,19 +1,31 @@
 use encoding_rs::*;
 use encoding_rs_io::DecodeReaderBytesBuilder;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
+pub struct Resumo {
+    pub(crate) cnpj: String,
+    pub(crate) summary: HashMap<(String, String, String), f64>,
+    // NCM of each (cnpj, cod_item).
+    pub(crate) produtos: HashMap<(String, String), String>,
+    pub(crate) vl_tot_debitos: f64,
+    pub(crate) vl_icms_recolher: f64,
+}
+
 fn to_f64(input: &Option<&String>) -> f64 {
     let replaced = input.unwrap().replace(',', ".");
     replaced.parse::<f64>().unwrap_or(0_f64)
 }
 
-pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64> {
+pub(crate) fn summarize(path: PathBuf) -> Resumo {
     let mut cnpj: String = String::new();
     let mut summary: HashMap<(String, String, String), f64> = HashMap::new();
+    let mut produtos: HashMap<(String, String), String> = HashMap::new();
+    let mut vl_tot_debitos: f64 = 0_f64;
+    let mut vl_icms_recolher: f64 = 0_f64;
 
     let file: File = File::open(&path).unwrap();
 
@@ -25,6 +37,15 @@ pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64>
 
     let mut cod_mod: String = String::new();
 
+    // C850 (each CF-e) and C890 (the daily totals of each SAT equipment) may report the same
+    // sales, so C890 only counts for the keys without C850.
+    let mut c850: HashSet<(String, String, String)> = HashSet::new();
+    let mut c890: HashMap<(String, String, String), f64> = HashMap::new();
+
+    // Likewise, D410 (the daily summary of D400) repeats the tickets consolidated in D300.
+    let mut d300: HashSet<(String, String, String)> = HashSet::new();
+    let mut d410: HashMap<(String, String, String), f64> = HashMap::new();
+
     for line in reader.lines() {
         let l = line.unwrap();
         let r: Vec<String> = l.split('|').map(|s| s.to_string()).collect();
@@ -33,6 +54,13 @@ pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64>
             match reg.as_str() {
                 "0000" => cnpj = r.get(7).unwrap().to_string(),
 
+                "0200" => {
+                    let cod_item: String = r.get(2).unwrap().to_string();
+                    let cod_ncm: String = r.get(8).unwrap().to_string();
+
+                    produtos.insert((cnpj.clone(), cod_item), cod_ncm);
+                }
+
                 "C100" => cod_mod = r.get(5).unwrap().to_string(),
 
                 "C190" => {
@@ -66,6 +94,72 @@ pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64>
                     *value += vl_icms;
                 }
 
+                "C800" => cod_mod = r.get(2).unwrap().to_string(),
+
+                "C850" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(7));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    c850.insert(key.clone());
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "C860" => cod_mod = r.get(2).unwrap().to_string(),
+
+                "C890" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(7));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    let value = c890.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "D100" => cod_mod = r.get(5).unwrap().to_string(),
+
+                "D190" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(7));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "D300" => {
+                    let cod_mod: String = r.get(2).unwrap().to_string();
+                    let cfop: String = r.get(8).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(17));
+
+                    let key = (cnpj.clone(), cod_mod, cfop);
+                    d300.insert(key.clone());
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "D350" => cod_mod = r.get(2).unwrap().to_string(),
+
+                "D390" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(10));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "D410" => {
+                    let cod_mod: String = r.get(2).unwrap().to_string();
+                    let cfop: String = r.get(9).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(15));
+
+                    let key = (cnpj.clone(), cod_mod, cfop);
+                    let value = d410.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
                 "D500" => cod_mod = r.get(5).unwrap().to_string(),
 
                 "D590" => {
@@ -77,8 +171,36 @@ pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64>
                     *value += vl_icms;
                 }
 
-                "C320" | "C390" | "C690" | "C790" | "C850" | "C890" | "D190" | "D300" | "D390"
-                | "D410" | "D690" | "D696" => {
+                "D600" => cod_mod = r.get(2).unwrap().to_string(),
+
+                "D690" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(7));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                "D695" => cod_mod = r.get(2).unwrap().to_string(),
+
+                "D696" => {
+                    let cfop: String = r.get(3).unwrap().to_string();
+                    let vl_icms: f64 = to_f64(&r.get(7));
+
+                    let key = (cnpj.clone(), cod_mod.clone(), cfop);
+                    let value = summary.entry(key).or_insert(0_f64);
+                    *value += vl_icms;
+                }
+
+                // Debits of the period (VL_TOT_DEBITOS) and ICMS payable (VL_ICMS_RECOLHER plus
+                // DEB_ESP)
+                "E110" => {
+                    vl_tot_debitos = to_f64(&r.get(2));
+                    vl_icms_recolher = to_f64(&r.get(13)) + to_f64(&r.get(15));
+                }
+
+                "C320" | "C390" | "C690" | "C790" => {
                     todo!("Registro {} não implantado", reg)
                 }
 
@@ -87,5 +209,23 @@ pub(crate) fn summarize(path: PathBuf) -> HashMap<(String, String, String), f64>
         }
     }
 
-    summary
+    for (key, vl_icms) in c890 {
+        if !c850.contains(&key) {
+            *summary.entry(key).or_insert(0_f64) += vl_icms;
+        }
+    }
+
+    for (key, vl_icms) in d410 {
+        if !d300.contains(&key) {
+            *summary.entry(key).or_insert(0_f64) += vl_icms;
+        }
+    }
+
+    Resumo {
+        cnpj,
+        summary,
+        produtos,
+        vl_tot_debitos,
+        vl_icms_recolher,
+    }
 }
@@ -3,16 +3,26 @@ use fltk::{app::*, button::*, dialog::*, enums::Font, prelude::*, window::*};
 use serde::Serialize;
 
 use fltk::output::MultilineOutput;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+mod ajuste;
+mod config;
+mod credito;
+mod dctf;
 mod efd_contribuicoes;
 mod efd_icms_ipi;
+mod monofasico;
+mod perdcomp;
+mod prescricao;
+mod razao;
+mod retificadora;
+mod selic;
 
 struct Console {
     display: MultilineOutput,
@@ -48,6 +58,9 @@ impl Console {
 
 #[derive(Serialize)]
 struct Sped {
+    #[serde(rename = "Empresa")]
+    empresa: String,
+
     #[serde(rename = "EFD Contribuições")]
     efd_contribuicoes: Option<PathBuf>,
 
@@ -56,63 +69,241 @@ struct Sped {
 
     #[serde(rename = "Competência")]
     competencia: Option<String>,
+
+    #[serde(rename = "Substituídos")]
+    substituidos: Vec<(PathBuf, String)>,
+}
+
+// Header of an uploaded file, used to group it and to find the effective file of each period.
+struct Arquivo {
+    path: PathBuf,
+    cnpj: String,
+    competencia: String,
+    contribuicoes: bool,
+    retificadora: bool,
+    num_rec_anterior: String,
+    // Receipt of the transmission, from `recibos` in the configuration.
+    recibo: Option<String>,
+    hash: [u8; 32],
+}
+
+// Receipt numbers are compared by their digits and letters, with or without the punctuation.
+fn recibo(numero: &str) -> String {
+    numero
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect()
+}
+
+impl Arquivo {
+    fn new(path: &Path, bytes: &[u8], recibos: &HashMap<String, String>) -> Option<Self> {
+        let first_line = bytes.split(|&b| b == b'\n').next()?;
+        let first_line = String::from_utf8_lossy(first_line);
+        let data: Vec<&str> = first_line.trim_end().split('|').collect();
+
+        let hash: [u8; 32] = Sha256::digest(bytes).into();
+
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let numero = recibos.get(filename.as_ref()).map(|numero| recibo(numero));
+
+        // Check CNPJ at index 9 for EFD Contribuições (TIPO_ESCRIT, NUM_REC_ANTERIOR, DT_FIN)
+        if is_cnpj_valid(data.get(9).unwrap_or(&"")) && is_dt_fin_valid(data.get(7).unwrap()) {
+            return Some(Self {
+                path: path.to_path_buf(),
+                cnpj: data.get(9).unwrap().to_string(),
+                competencia: data.get(7).unwrap().to_string(),
+                contribuicoes: true,
+                retificadora: *data.get(3).unwrap() == "1",
+                num_rec_anterior: recibo(data.get(5).unwrap()),
+                recibo: numero,
+                hash,
+            });
+        }
+
+        // Check CNPJ at index 7 for EFD ICMS/IPI (COD_FIN, DT_FIN)
+        if is_cnpj_valid(data.get(7).unwrap_or(&"")) && is_dt_fin_valid(data.get(5).unwrap()) {
+            return Some(Self {
+                path: path.to_path_buf(),
+                cnpj: data.get(7).unwrap().to_string(),
+                competencia: data.get(5).unwrap().to_string(),
+                contribuicoes: false,
+                retificadora: *data.get(3).unwrap() == "1",
+                num_rec_anterior: String::new(),
+                recibo: numero,
+                hash,
+            });
+        }
+
+        None
+    }
+
+    fn filename(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
 }
 
 impl Sped {
-    fn new(filenames: Vec<PathBuf>) -> Self {
-        let mut efd_contribuicoes: Option<PathBuf> = None;
-        let mut efd_icms_ipi: Vec<PathBuf> = Vec::new();
-        let mut competencia: Option<String> = None;
+    fn new(empresa: String, competencia: String) -> Self {
+        Self {
+            empresa,
+            efd_contribuicoes: None,
+            efd_icms_ipi: Vec::new(),
+            competencia: Some(competencia),
+            substituidos: Vec::new(),
+        }
+    }
+
+    // Groups the files by company (CNPJ root) and competência, in chronological order. Files
+    // that don't belong to a group with an EFD Contribuições are returned apart.
+    //
+    // Within a group, each file (EFD Contribuições, or the EFD ICMS/IPI of an establishment) may
+    // have been uploaded as the original and its retifying files. Only the effective file is
+    // used, and the superseded ones are kept for the report.
+    fn group(
+        filenames: Vec<PathBuf>,
+        recibos: &HashMap<String, String>,
+    ) -> (Vec<Self>, Vec<PathBuf>) {
+        let mut arquivos: Vec<Arquivo> = Vec::new();
+        let mut nao_atribuidos: Vec<PathBuf> = Vec::new();
 
         for path in filenames {
-            if let Ok(file) = File::open(&path) {
-                let reader = BufReader::new(file);
-
-                if let Some(Ok(first_line)) = reader.lines().next() {
-                    let data: Vec<&str> = first_line.split('|').collect();
-
-                    if competencia.is_none() {
-                        // Check CNPJ at index 9 for EFD Contribuições
-                        if is_cnpj_valid(data.get(9).unwrap()) {
-                            efd_contribuicoes = Some(path.clone());
-                            competencia = Some(data.get(7).unwrap().to_string())
-                        }
-
-                        // Check CNPJ at index 7 for EFD ICMS/IPI
-                        if is_cnpj_valid(data.get(7).unwrap()) {
-                            efd_icms_ipi.push(path.clone());
-                            competencia = Some(data.get(5).unwrap().to_string())
-                        }
-                    } else {
-                        // Check CNPJ at index 9 for EFD Contribuições
-                        if is_cnpj_valid(data.get(9).unwrap())
-                            && competencia == Some(data.get(7).unwrap().to_string())
-                        {
-                            efd_contribuicoes = Some(path.clone());
-                        }
-
-                        // Check CNPJ at index 7 for EFD ICMS/IPI
-                        if is_cnpj_valid(data.get(7).unwrap())
-                            && competencia == Some(data.get(5).unwrap().to_string())
-                        {
-                            efd_icms_ipi.push(path.clone());
-                        }
+            let arquivo = fs::read(&path)
+                .ok()
+                .and_then(|bytes| Arquivo::new(&path, &bytes, recibos));
+            match arquivo {
+                Some(arquivo) => arquivos.push(arquivo),
+                None => nao_atribuidos.push(path),
+            }
+        }
+
+        Self::agrupar(arquivos, nao_atribuidos)
+    }
+
+    fn agrupar(
+        arquivos: Vec<Arquivo>,
+        mut nao_atribuidos: Vec<PathBuf>,
+    ) -> (Vec<Self>, Vec<PathBuf>) {
+        let mut cadeias: HashMap<(String, String, bool, String), Vec<Arquivo>> = HashMap::new();
+
+        for arquivo in arquivos {
+            let key = (
+                arquivo.cnpj[..8].to_string(),
+                arquivo.competencia.clone(),
+                arquivo.contribuicoes,
+                // EFD Contribuições is filed by the head office, EFD ICMS/IPI by each
+                // establishment.
+                if arquivo.contribuicoes {
+                    String::new()
+                } else {
+                    arquivo.cnpj.clone()
+                },
+            );
+            cadeias.entry(key).or_default().push(arquivo);
+        }
+
+        let mut speds: HashMap<(String, String), Sped> = HashMap::new();
+
+        for ((empresa, competencia, contribuicoes, _), mut cadeia) in cadeias {
+            cadeia.sort_by_key(|arquivo| arquivo.filename());
+
+            let sped = speds
+                .entry((empresa.clone(), competencia.clone()))
+                .or_insert_with(|| Sped::new(empresa, competencia));
+
+            // Copies of the same file are kept once.
+            let mut arquivos: Vec<Arquivo> = Vec::new();
+            for arquivo in cadeia {
+                match arquivos.iter().find(|a| a.hash == arquivo.hash) {
+                    Some(original) => {
+                        let motivo = format!("duplicado de {}", original.filename());
+                        sped.substituidos.push((arquivo.path, motivo));
                     }
+                    None => arquivos.push(arquivo),
                 }
             }
+
+            // A file is superseded by the one whose NUM_REC_ANTERIOR is its receipt. Among the
+            // files not superseded that way, a retifying file wins over the original, and then
+            // the last file name, which is reported as an assumption.
+            let anteriores: HashMap<String, String> = arquivos
+                .iter()
+                .filter(|arquivo| !arquivo.num_rec_anterior.is_empty())
+                .map(|arquivo| (arquivo.num_rec_anterior.clone(), arquivo.filename()))
+                .collect();
+            let (mut retificados, mut candidatos): (Vec<Arquivo>, Vec<Arquivo>) =
+                arquivos.into_iter().partition(|arquivo| {
+                    arquivo
+                        .recibo
+                        .as_ref()
+                        .is_some_and(|numero| anteriores.contains_key(numero))
+                });
+            // Receipts that point at each other can't tell which file is the last.
+            if candidatos.is_empty() {
+                candidatos = std::mem::take(&mut retificados);
+            }
+            candidatos.sort_by_key(|arquivo| arquivo.retificadora);
+
+            let efetivo = candidatos.pop().unwrap();
+
+            for arquivo in candidatos {
+                let motivo = format!(
+                    "substituído por {} (sem recibo em `recibos` que encadeie as escriturações; adotada a {} de maior nome)",
+                    efetivo.filename(),
+                    if efetivo.retificadora {
+                        "retificadora"
+                    } else {
+                        "escrituração"
+                    }
+                );
+                sped.substituidos.push((arquivo.path, motivo));
+            }
+
+            for arquivo in retificados {
+                let numero = arquivo.recibo.unwrap_or_default();
+                let motivo = format!(
+                    "retificado por {} (NUM_REC_ANTERIOR {})",
+                    anteriores[&numero], numero
+                );
+                sped.substituidos.push((arquivo.path, motivo));
+            }
+
+            if contribuicoes {
+                sped.efd_contribuicoes = Some(efetivo.path);
+            } else {
+                sped.efd_icms_ipi.push(efetivo.path);
+            }
         }
 
-        Self {
-            efd_contribuicoes,
-            efd_icms_ipi,
-            competencia,
+        let mut grupos: Vec<Sped> = Vec::new();
+        for (_, mut sped) in speds {
+            if sped.efd_contribuicoes.is_some() {
+                sped.efd_icms_ipi.sort();
+                sped.substituidos.sort();
+                grupos.push(sped);
+            } else {
+                nao_atribuidos.extend(sped.efd_icms_ipi);
+                nao_atribuidos.extend(sped.substituidos.into_iter().map(|(path, _)| path));
+            }
         }
+
+        grupos.sort_by_key(|sped| {
+            let competencia = sped.competencia.as_deref().unwrap_or_default();
+            (sped.empresa.clone(), periodo(competencia))
+        });
+        nao_atribuidos.sort();
+
+        (grupos, nao_atribuidos)
     }
 }
 
 impl fmt::Display for Sped {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "Arquivos em análise:")?;
+        writeln!(f, "- Empresa (CNPJ base): {}", self.empresa)?;
         if let Some(competencia) = &self.competencia {
             writeln!(f, "- Competência: {}", competencia)?;
         } else {
@@ -137,6 +328,13 @@ impl fmt::Display for Sped {
                 writeln!(f, "  - {}", filename)?;
             }
         }
+        if !self.substituidos.is_empty() {
+            writeln!(f, "- Arquivos substituídos:")?;
+            for (path, motivo) in &self.substituidos {
+                let filename = path.file_name().unwrap_or_default().to_string_lossy();
+                writeln!(f, "  - {} ({})", filename, motivo)?;
+            }
+        }
         Ok(())
     }
 }
@@ -187,21 +385,154 @@ fn is_cnpj_valid(cnpj: &str) -> bool {
     digits[12] == first_check_digit && digits[13] == second_check_digit
 }
 
-fn process_files(console: Rc<RefCell<Console>>, files: Vec<PathBuf>) {
+// DT_FIN in ddmmaaaa, so that `periodo` can slice it. Files with another date aren't grouped.
+fn is_dt_fin_valid(dt_fin: &str) -> bool {
+    dt_fin.len() == 8
+        && dt_fin.bytes().all(|b| b.is_ascii_digit())
+        && (1..=12).contains(&dt_fin[2..4].parse::<u32>().unwrap_or(0))
+}
+
+// DT_FIN (ddmmaaaa) as aaaamm, so that competências sort chronologically.
+fn periodo(competencia: &str) -> String {
+    if competencia.len() == 8 {
+        format!("{}{}", &competencia[4..8], &competencia[2..4])
+    } else {
+        competencia.to_string()
+    }
+}
+
+struct Economia {
+    competencia: String,
+    pis: f64,
+    cofins: f64,
+    situacao: prescricao::Situacao,
+    creditos: Vec<perdcomp::Credito>,
+    divergente: bool,
+    efd_contribuicoes: PathBuf,
+}
+
+fn process_files(console: Rc<RefCell<Console>>, files: Vec<PathBuf>, config: &config::Config) {
     console.borrow_mut().clear();
 
-    let files = Sped::new(files);
+    let (speds, nao_atribuidos) = Sped::group(files, &config.recibos);
+
+    let mut empresa: Option<String> = None;
+    let mut economias: Vec<Economia> = Vec::new();
 
-    console.borrow_mut().add_line(files.to_string());
+    let debitos = config
+        .dctf
+        .as_deref()
+        .map(dctf::load)
+        .transpose()
+        .unwrap_or_else(|erro| {
+            console
+                .borrow_mut()
+                .add_line(format!("{} A conferência com a DCTF não será feita.", erro));
+            console.borrow_mut().add_line("\n".to_string());
+            None
+        });
 
+    // Whether to go on without the missing EFD ICMS/IPI files, asked once for the batch.
+    let mut sem_efd_icms_ipi: Option<bool> = None;
+
+    for sped in speds {
+        if empresa.as_ref() != Some(&sped.empresa) {
+            if economias.len() > 1 {
+                consolidar(&console, &economias);
+            }
+            if let Some(selic) = &config.selic {
+                atualizar(&console, &economias, selic);
+            }
+            if let (Some(perdcomp), Some(empresa)) = (&config.perdcomp, &empresa) {
+                exportar(&console, empresa, &economias, perdcomp);
+            }
+            if let (Some(razao), Some(empresa)) = (&config.razao, &empresa) {
+                escriturar(&console, empresa, &economias, razao, config);
+            }
+            economias.clear();
+
+            empresa = Some(sped.empresa.clone());
+            console
+                .borrow_mut()
+                .add_line(format!("Empresa {}", sped.empresa));
+            console.borrow_mut().add_line("\n".to_string());
+        }
+
+        console.borrow_mut().add_line(sped.to_string());
+
+        let acao = config.acoes.get(&sped.empresa);
+
+        if let Some(mut economia) = analisar(
+            &console,
+            sped,
+            config,
+            debitos.as_ref(),
+            &mut sem_efd_icms_ipi,
+        ) {
+            if let Some(acao) = acao {
+                economia.situacao = prescricao::situacao(&economia.competencia, acao);
+                console.borrow_mut().add_line(format!(
+                    "Situação da competência: {} (ajuizamento em {})",
+                    economia.situacao, acao.ajuizamento
+                ));
+                console.borrow_mut().add_line("\n".to_string());
+            }
+            economias.push(economia);
+        }
+    }
+
+    if economias.len() > 1 {
+        consolidar(&console, &economias);
+    }
+    if let Some(selic) = &config.selic {
+        atualizar(&console, &economias, selic);
+    }
+    if let (Some(perdcomp), Some(empresa)) = (&config.perdcomp, &empresa) {
+        exportar(&console, empresa, &economias, perdcomp);
+    }
+    if let (Some(razao), Some(empresa)) = (&config.razao, &empresa) {
+        escriturar(&console, empresa, &economias, razao, config);
+    }
+
+    if !nao_atribuidos.is_empty() {
+        console
+            .borrow_mut()
+            .add_line("Arquivos não atribuídos".to_string());
+        for path in nao_atribuidos {
+            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+            console.borrow_mut().add_line(format!("- {}", filename));
+        }
+    }
+}
+
+fn analisar(
+    console: &Rc<RefCell<Console>>,
+    files: Sped,
+    config: &config::Config,
+    debitos: Option<&dctf::Debitos>,
+    sem_efd_icms_ipi: &mut Option<bool>,
+) -> Option<Economia> {
     let mut efd_icms_ipi: HashMap<(String, String, String), f64> = HashMap::new();
+    let mut produtos: HashMap<(String, String), String> = HashMap::new();
+    let mut cnpjs: Vec<(String, PathBuf)> = Vec::new();
+    // VL_TOT_DEBITOS and the ICMS payable of E110, by branch.
+    let mut e110: HashMap<String, (f64, f64)> = HashMap::new();
 
     // Iterate over EFD ICMS/IPI files and append summaries to efd_icms_ipi
     for path in files.efd_icms_ipi {
-        let summary = efd_icms_ipi::summarize(path);
-        efd_icms_ipi.extend(summary);
+        let resumo = efd_icms_ipi::summarize(path.clone());
+        efd_icms_ipi.extend(resumo.summary);
+        produtos.extend(resumo.produtos);
+        e110.insert(
+            resumo.cnpj.clone(),
+            (resumo.vl_tot_debitos, resumo.vl_icms_recolher),
+        );
+        cnpjs.push((resumo.cnpj, path));
     }
 
+    let efd_contribuicoes = files.efd_contribuicoes.unwrap();
+    cobertura(console, &efd_contribuicoes, &cnpjs, sem_efd_icms_ipi)?;
+
     console
         .borrow_mut()
         .add_line("Arquivos EFD ICMS/IPI".to_string());
@@ -221,99 +552,1144 @@ fn process_files(console: Rc<RefCell<Console>>, files: Vec<PathBuf>) {
     }
     console.borrow_mut().add_line("\n".to_string());
 
-    let (efd_contribuicoes, m210, m610) =
-        efd_contribuicoes::summarize(files.efd_contribuicoes.unwrap(), efd_icms_ipi.clone());
+    let resumo = efd_contribuicoes::summarize(
+        efd_contribuicoes.clone(),
+        efd_icms_ipi.clone(),
+        produtos,
+        config,
+    );
 
     console
         .borrow_mut()
         .add_line("Arquivos EFD Contribuições".to_string());
-    if efd_contribuicoes.is_empty() {
+    if resumo.summary.is_empty() {
         console
             .borrow_mut()
             .add_line("- Não foram apresentados arquivos EFD Contribuições".to_string());
     } else {
-        for (key, value) in efd_contribuicoes.iter() {
-            if *value > 0_f64 {
+        let mut keys: Vec<&(String, String, String)> = resumo.summary.keys().collect();
+        keys.sort();
+
+        let mut filial: Option<&String> = None;
+        for key in keys {
+            if filial != Some(&key.0) {
+                filial = Some(&key.0);
+                let vl_icms: f64 = resumo
+                    .summary
+                    .iter()
+                    .filter(|(k, _)| k.0 == key.0)
+                    .fold(0_f64, |acc, (_, &value)| acc + value);
                 console
                     .borrow_mut()
-                    .add_line(format!("- REG: {}; VL_ICMS: {:.2}", key, value));
+                    .add_line(format!("- FILIAL: {}; VL_ICMS: {:.2}", key.0, vl_icms));
+            }
+
+            let value = resumo.summary[key];
+            if value > 0_f64 {
+                console.borrow_mut().add_line(format!(
+                    "  - REG: {}; CST: {}; VL_ICMS: {:.2}",
+                    key.1, key.2, value
+                ));
             }
         }
     }
     console.borrow_mut().add_line("\n".to_string());
 
-    let vl_icms: f64 = efd_contribuicoes
-        .values()
-        .fold(0_f64, |acc, &value| acc + value);
-    let vl_rec_brt: f64 = m210
+    if !resumo.f100.is_empty() {
+        console.borrow_mut().add_line("Registros F100".to_string());
+        for linha in resumo.f100.iter() {
+            let situacao = match linha.vl_icms {
+                Some(vl_icms) => format!("incluído; VL_ICMS: {:.2}", vl_icms),
+                None => "excluído".to_string(),
+            };
+            console.borrow_mut().add_line(format!(
+                "- FILIAL: {}; COD_CTA: {}; CST: {}; VL_OPER: {:.2}; {}",
+                linha.cnpj, linha.cod_cta, linha.cst, linha.vl_oper, situacao
+            ));
+        }
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    if !resumo.monofasicos.is_empty() {
+        let situacao = if config.monofasico.reclassificar {
+            "reclassificado para CST 04"
+        } else {
+            "mantido no cálculo"
+        };
+        console
+            .borrow_mut()
+            .add_line("Produtos monofásicos com CST 01".to_string());
+        for item in resumo.monofasicos.iter() {
+            console.borrow_mut().add_line(format!(
+                "- FILIAL: {}; REG: {}; COD_ITEM: {}; NCM: {}; VL_OPR: {:.2}; {}",
+                item.cnpj, item.reg, item.cod_item, item.ncm, item.vl_opr, situacao
+            ));
+        }
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    let regime = &resumo.regime;
+    let cod_inc_trib = match regime.cod_inc_trib.as_str() {
+        "1" => "exclusivamente não cumulativo",
+        "2" => "exclusivamente cumulativo",
+        "3" => "não cumulativo e cumulativo",
+        _ => "não informado",
+    };
+    console
+        .borrow_mut()
+        .add_line("Regime de apuração (0110)".to_string());
+    console.borrow_mut().add_line(format!(
+        "- COD_INC_TRIB: {} ({}); IND_APRO_CRED: {}; COD_TIPO_CONT: {}",
+        regime.cod_inc_trib, cod_inc_trib, regime.ind_apro_cred, regime.cod_tipo_cont
+    ));
+    // The regime of each line follows its COD_CONT, 0110 is only checked against it.
+    if resumo
+        .m210
         .iter()
-        .fold(0_f64, |acc, apuracao| acc + apuracao.vl_rec_brt);
+        .chain(resumo.m610.iter())
+        .any(|apuracao| regime.diverge(apuracao))
+    {
+        console.borrow_mut().add_line(
+            "- Atenção: há apurações em M210/M610 cujo COD_CONT diverge do regime informado em 0110; considerado o COD_CONT."
+                .to_string(),
+        );
+    }
+    console.borrow_mut().add_line("\n".to_string());
 
     let mut pis: f64 = 0_f64;
     let mut cofins: f64 = 0_f64;
+    let mut economia_filial: HashMap<String, (f64, f64)> = HashMap::new();
+    let mut recuperado: HashMap<&str, f64> = HashMap::new();
+
+    let item = config.exclusao == config::Exclusao::Item;
+    let mut exclusoes: Vec<ajuste::Exclusao> = Vec::new();
+
+    // ICMS by CST and rate of each contribution, in the item-level exclusion.
+    let mut itens_pis: HashMap<(String, String), f64> = HashMap::new();
+    let mut itens_cofins: HashMap<(String, String), f64> = HashMap::new();
+    if item {
+        for ((_, tributo, cst, aliq), value) in resumo.itens.iter() {
+            let itens = if tributo == "PIS" {
+                &mut itens_pis
+            } else {
+                &mut itens_cofins
+            };
+            *itens.entry((cst.clone(), aliq.clone())).or_insert(0_f64) += value;
+        }
+    }
+
+    // ICMS the taxpayer already left out of the base of the C170 items, by CST and rate.
+    let mut excluido_pis: HashMap<(String, String), f64> = HashMap::new();
+    let mut excluido_cofins: HashMap<(String, String), f64> = HashMap::new();
+    for ((tributo, cst, aliq), value) in resumo.excluido.iter() {
+        let excluido = if tributo == "PIS" {
+            &mut excluido_pis
+        } else {
+            &mut excluido_cofins
+        };
+        *excluido.entry((cst.clone(), aliq.clone())).or_insert(0_f64) += value;
+    }
+
+    // The ICMS of each CST is first split between the regimes by their share of the gross
+    // revenue of that CST, and then across the M210/M610 lines of each regime (see `exclusao`).
+    let regimes = [("não cumulativo", false), ("cumulativo", true)];
+    for (titulo, cumulativo) in regimes {
+        if !resumo
+            .m210
+            .iter()
+            .chain(resumo.m610.iter())
+            .any(|apuracao| apuracao.cumulativo() == cumulativo)
+        {
+            continue;
+        }
 
-    console
-        .borrow_mut()
-        .add_line("Analisando registro M210 (CST 01)...".to_string());
-    for i in m210 {
-        let proporcao = i.vl_rec_brt / vl_rec_brt;
         console
             .borrow_mut()
-            .add_line(format!("- Base de cálculo original: {:.2}", i.vl_bc_cont));
-        let icms = vl_icms * proporcao;
+            .add_line(format!("Regime {}...", titulo));
+        console.borrow_mut().add_line("\n".to_string());
+
+        let mut pis_regime: f64 = 0_f64;
+        let mut cofins_regime: f64 = 0_f64;
+
+        for cst in efd_contribuicoes::CST_TRIBUTADO {
+            let rateio_pis: f64 = vl_icms_cst(&resumo.summary, cst, item);
+            let rateio_cofins: f64 = vl_icms_cst(&resumo.summary_cofins, cst, item);
+            let vl_rec_brt: f64 = resumo
+                .m210
+                .iter()
+                .filter(|apuracao| apuracao.cst() == cst)
+                .fold(0_f64, |acc, apuracao| acc + apuracao.vl_rec_brt);
+
+            let m210: Vec<&efd_contribuicoes::Apuracao> = resumo
+                .m210
+                .iter()
+                .filter(|apuracao| apuracao.cumulativo() == cumulativo && apuracao.cst() == cst)
+                .collect();
+            let m610: Vec<&efd_contribuicoes::Apuracao> = resumo
+                .m610
+                .iter()
+                .filter(|apuracao| apuracao.cumulativo() == cumulativo && apuracao.cst() == cst)
+                .collect();
+
+            if m210.is_empty() && m610.is_empty() {
+                continue;
+            }
+
+            let vl_rec_brt_regime: f64 = m210
+                .iter()
+                .fold(0_f64, |acc, apuracao| acc + apuracao.vl_rec_brt);
+            let proporcao_regime: f64 = if vl_rec_brt > 0_f64 {
+                vl_rec_brt_regime / vl_rec_brt
+            } else {
+                0_f64
+            };
+
+            let icms_m210 = exclusao(
+                &resumo.m210,
+                &m210,
+                cst,
+                rateio_pis,
+                proporcao_regime,
+                &itens_pis,
+            );
+            let icms_m610 = exclusao(
+                &resumo.m610,
+                &m610,
+                cst,
+                rateio_cofins,
+                proporcao_regime,
+                &itens_cofins,
+            );
+
+            let excluido_m210 =
+                ja_excluido(&resumo.m210, &m210, cst, proporcao_regime, &excluido_pis);
+            let excluido_m610 =
+                ja_excluido(&resumo.m610, &m610, cst, proporcao_regime, &excluido_cofins);
+
+            console.borrow_mut().add_line(format!(
+                "- ICMS atribuído ao regime (CST {}): {:.2}",
+                cst,
+                icms_m210.iter().sum::<f64>()
+            ));
+            console.borrow_mut().add_line("\n".to_string());
+
+            let icms_m210: Vec<f64> = restante(&icms_m210, &excluido_m210);
+            let icms_m610: Vec<f64> = restante(&icms_m610, &excluido_m610);
+
+            let pis_cst = apurar(
+                console,
+                "M210",
+                "PIS",
+                cst,
+                &m210,
+                &icms_m210,
+                &excluido_m210,
+            );
+            let cofins_cst = apurar(
+                console,
+                "M610",
+                "COFINS",
+                cst,
+                &m610,
+                &icms_m610,
+                &excluido_m610,
+            );
+
+            // Each branch gets the savings in proportion to its share of the ICMS of the CST.
+            let filiais_pis = filiais(&resumo.summary, cst);
+            let filiais_cofins = filiais(&resumo.summary_cofins, cst);
+            for (cnpj, proporcao) in filiais_pis.iter() {
+                let economia = economia_filial
+                    .entry(cnpj.clone())
+                    .or_insert((0_f64, 0_f64));
+                economia.0 += pis_cst * proporcao;
+            }
+            for (cnpj, proporcao) in filiais_cofins.iter() {
+                let economia = economia_filial
+                    .entry(cnpj.clone())
+                    .or_insert((0_f64, 0_f64));
+                economia.1 += cofins_cst * proporcao;
+            }
+
+            for (reg, apuracoes, icms, filiais) in [
+                ("M215", &m210, &icms_m210, &filiais_pis),
+                ("M615", &m610, &icms_m610, &filiais_cofins),
+            ] {
+                for (apuracao, &vl_icms) in apuracoes.iter().zip(icms.iter()) {
+                    exclusoes.push(ajuste::Exclusao {
+                        reg,
+                        apuracao,
+                        vl_icms,
+                        filiais: filiais.clone(),
+                    });
+                }
+            }
+
+            pis_regime += pis_cst;
+            cofins_regime += cofins_cst;
+        }
+
         console
             .borrow_mut()
-            .add_line(format!("  ICMS a ser excluído: {:.2}", icms));
-        let economia = (icms * i.aliq_cont) / 100_f64;
-        pis += economia;
+            .add_line(format!("Economia tributária do regime {}...", titulo));
+        console
+            .borrow_mut()
+            .add_line(format!("- PIS: {:.2}", pis_regime));
         console
             .borrow_mut()
-            .add_line(format!("  Economia tributária (PIS): {:.2}", economia));
+            .add_line(format!("- COFINS: {:.2}", cofins_regime));
         console.borrow_mut().add_line("\n".to_string());
+
+        pis += pis_regime;
+        cofins += cofins_regime;
+
+        *recuperado
+            .entry(perdcomp::codigo("PIS", cumulativo))
+            .or_insert(0_f64) += pis_regime;
+        *recuperado
+            .entry(perdcomp::codigo("COFINS", cumulativo))
+            .or_insert(0_f64) += cofins_regime;
+    }
+
+    console
+        .borrow_mut()
+        .add_line("Economia tributária por estabelecimento...".to_string());
+    let mut filiais: Vec<(&String, &(f64, f64))> = economia_filial.iter().collect();
+    filiais.sort_by(|a, b| a.0.cmp(b.0));
+    for (cnpj, (pis, cofins)) in filiais.iter() {
+        console.borrow_mut().add_line(format!(
+            "- FILIAL: {}; PIS: {:.2}; COFINS: {:.2}",
+            cnpj, pis, cofins
+        ));
+    }
+    console.borrow_mut().add_line("\n".to_string());
+
+    // The ICMS payable of each branch (E110) takes the place of its ICMS destacado, allocated to
+    // the same CSTs and regimes. The share of the debits of the period (VL_TOT_DEBITOS) that is
+    // payable, at most all of it, scales the savings of the branch.
+    console.borrow_mut().add_line(
+        "Comparação ICMS destacado x ICMS a recolher (Solução de Consulta Cosit 13/2018)..."
+            .to_string(),
+    );
+    let mut total_destacado: f64 = 0_f64;
+    let mut total_recolher: f64 = 0_f64;
+    for (cnpj, (pis, cofins)) in filiais {
+        let vl_icms_destacado: f64 = resumo
+            .summary
+            .iter()
+            .filter(|(key, _)| &key.0 == cnpj)
+            .fold(0_f64, |acc, (_, &value)| acc + value);
+        let (vl_tot_debitos, vl_icms_a_recolher) = *e110.get(cnpj).unwrap_or(&(0_f64, 0_f64));
+
+        let proporcao = if vl_tot_debitos > 0_f64 {
+            (vl_icms_a_recolher / vl_tot_debitos).min(1_f64)
+        } else {
+            0_f64
+        };
+        let destacado = pis + cofins;
+        let recolher = destacado * proporcao;
+
+        console.borrow_mut().add_line(format!(
+            "- FILIAL: {}; ICMS DESTACADO: {:.2}; VL_TOT_DEBITOS: {:.2}; ICMS A RECOLHER: {:.2}; ECONOMIA DESTACADO: {:.2}; ECONOMIA A RECOLHER: {:.2}; DIFERENÇA: {:.2}",
+            cnpj,
+            vl_icms_destacado,
+            vl_tot_debitos,
+            vl_icms_a_recolher,
+            destacado,
+            recolher,
+            destacado - recolher
+        ));
+
+        total_destacado += destacado;
+        total_recolher += recolher;
     }
+    console.borrow_mut().add_line(format!(
+        "- TOTAL; ECONOMIA DESTACADO: {:.2}; ECONOMIA A RECOLHER: {:.2}; DIFERENÇA: {:.2}",
+        total_destacado,
+        total_recolher,
+        total_destacado - total_recolher
+    ));
 
+    console.borrow_mut().add_line("\n".to_string());
+    console
+        .borrow_mut()
+        .add_line("Economia tributária total...".to_string());
+    console.borrow_mut().add_line(format!("- PIS: {:.2}", pis));
     console
         .borrow_mut()
-        .add_line("Analisando registro M610 (CST 01)...".to_string());
-    for i in m610 {
-        let proporcao = i.vl_rec_brt / vl_rec_brt;
+        .add_line(format!("- COFINS: {:.2}", cofins));
+    console.borrow_mut().add_line("\n".to_string());
+
+    let competencia = files.competencia.unwrap_or_default();
+
+    if let Some(ajuste) = &config.ajuste {
+        // M210 lines first, then M610, as in the file.
+        exclusoes.sort_by_key(|exclusao| exclusao.reg);
+
         console
             .borrow_mut()
-            .add_line(format!("- Base de cálculo original: {:.2}", i.vl_bc_cont));
-        let icms = vl_icms * proporcao;
+            .add_line("Ajustes da base de cálculo (M215/M615)...".to_string());
+
+        let registros = ajuste::registros(ajuste, &competencia, &exclusoes);
+        if registros.is_empty() {
+            console
+                .borrow_mut()
+                .add_line("- Nenhum ajuste M215/M615 a gravar".to_string());
+        } else {
+            let path = efd_contribuicoes.with_file_name(format!(
+                "{}_M215_M615.txt",
+                efd_contribuicoes
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ));
+
+            ajuste::gravar(&path, &registros);
+
+            console
+                .borrow_mut()
+                .add_line(format!("- Arquivo: {}", path.display()));
+        }
+
+        if exclusoes
+            .iter()
+            .any(|exclusao| exclusao.apuracao.layout_antigo())
+        {
+            console.borrow_mut().add_line(
+                "- M210/M610 no leiaute anterior a 2019, sem M215/M615: a redução só pode ser lançada diretamente no VL_BC_CONT, o que é feito na retificadora"
+                    .to_string(),
+            );
+        }
+
+        if ajuste.retificadora {
+            let filename = efd_contribuicoes
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+
+            // The receipt in `recibos`, asked for only when it is missing.
+            let num_rec_anterior = match config.recibos.get(&filename) {
+                Some(numero) => numero.clone(),
+                None => input_default(
+                    &format!(
+                        "Número do recibo da escrituração a retificar ({}):",
+                        filename
+                    ),
+                    "",
+                )
+                .unwrap_or_default(),
+            };
+
+            if num_rec_anterior.trim().is_empty() {
+                console.borrow_mut().add_line(
+                    "- Retificadora não gerada: recibo da escrituração anterior não informado"
+                        .to_string(),
+                );
+            } else {
+                let destino = efd_contribuicoes.with_file_name(format!(
+                    "{}_retificadora.txt",
+                    efd_contribuicoes
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ));
+
+                match retificadora::gerar(
+                    &efd_contribuicoes,
+                    &destino,
+                    ajuste,
+                    &competencia,
+                    &exclusoes,
+                    num_rec_anterior.trim(),
+                ) {
+                    Ok(()) => console
+                        .borrow_mut()
+                        .add_line(format!("- Retificadora: {}", destino.display())),
+                    Err(erro) => console
+                        .borrow_mut()
+                        .add_line(format!("- Retificadora não gerada: {}", erro)),
+                }
+            }
+        }
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    if periodo(&competencia).as_str() >= credito::INICIO {
+        let reducao = &resumo.reducao;
+
+        let mut keys: Vec<&(String, String, String)> = reducao.keys().collect();
+        keys.sort();
+
         console
             .borrow_mut()
-            .add_line(format!("  ICMS a ser excluído: {:.2}", icms));
-        let economia = (icms * i.aliq_cont) / 100_f64;
-        cofins += economia;
+            .add_line("Redução de créditos (Lei 14.592/2023)...".to_string());
+        for key in keys {
+            let value = &reducao[key];
+            console.borrow_mut().add_line(format!(
+                "- FILIAL: {}; REG: {}; CST: {}; VL_ICMS: {:.2}; PIS: {:.2}; COFINS: {:.2}",
+                key.0, key.1, key.2, value.vl_icms, value.pis, value.cofins
+            ));
+        }
+        console.borrow_mut().add_line("\n".to_string());
+
+        let pis_reducao: f64 = reducao.values().fold(0_f64, |acc, value| acc + value.pis);
+        let cofins_reducao: f64 = reducao
+            .values()
+            .fold(0_f64, |acc, value| acc + value.cofins);
+
+        // Each tax is reduced down to zero at most; what is left of the reduction is reported.
+        let mut excedentes: Vec<(&str, f64)> = Vec::new();
+        for (tributo, reducao, economia) in [
+            ("PIS", pis_reducao, &mut pis),
+            ("COFINS", cofins_reducao, &mut cofins),
+        ] {
+            // Credits only exist in the non-cumulative regime.
+            let credito = recuperado
+                .entry(perdcomp::codigo(tributo, false))
+                .or_insert(0_f64);
+            let abatido = reducao.min(credito.max(0_f64));
+
+            *credito -= abatido;
+            *economia -= abatido;
+
+            if reducao - abatido > 0.005 {
+                excedentes.push((tributo, reducao - abatido));
+            }
+        }
+
+        console.borrow_mut().add_line(
+            "Economia tributária líquida (exclusão menos redução de créditos)...".to_string(),
+        );
+        console.borrow_mut().add_line(format!("- PIS: {:.2}", pis));
+        console
+            .borrow_mut()
+            .add_line(format!("- COFINS: {:.2}", cofins));
+        for (tributo, excedente) in excedentes {
+            console.borrow_mut().add_line(format!(
+                "- Redução de créditos de {} maior que a exclusão: {:.2} não abatidos da economia; revisar",
+                tributo, excedente
+            ));
+        }
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    let mut divergente = false;
+
+    if let Some(debitos) = debitos {
+        let periodo = periodo(&competencia);
+
+        let mut codigos: Vec<&String> = resumo
+            .apurado
+            .keys()
+            .chain(
+                debitos
+                    .keys()
+                    .filter(|key| key.0 == files.empresa && key.1 == periodo)
+                    .map(|key| &key.2),
+            )
+            .collect();
+        codigos.sort();
+        codigos.dedup();
+
+        console
+            .borrow_mut()
+            .add_line("Conferência com a DCTF...".to_string());
+        for codigo in codigos {
+            let apurado = *resumo.apurado.get(codigo).unwrap_or(&0_f64);
+            let declarado = *debitos
+                .get(&(files.empresa.clone(), periodo.clone(), codigo.clone()))
+                .unwrap_or(&0_f64);
+
+            if apurado == 0_f64 && declarado == 0_f64 {
+                continue;
+            }
+
+            let situacao = if (apurado - declarado).abs() < 0.01_f64 {
+                "conferido"
+            } else {
+                divergente = true;
+                "DIVERGENTE"
+            };
+            console.borrow_mut().add_line(format!(
+                "- CÓDIGO: {}; M200/M600: {:.2}; DCTF: {:.2}; DIFERENÇA: {:.2}; {}",
+                codigo,
+                apurado,
+                declarado,
+                apurado - declarado,
+                situacao
+            ));
+        }
+        if divergente {
+            console.borrow_mut().add_line(
+                "- Há débitos divergentes: conferir a DCTF antes de pleitear o crédito".to_string(),
+            );
+        }
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    let mut creditos: Vec<perdcomp::Credito> = recuperado
+        .into_iter()
+        .map(|(codigo, recuperado)| perdcomp::Credito {
+            competencia: competencia.clone(),
+            codigo: codigo.to_string(),
+            debito: *resumo.debitos.get(codigo).unwrap_or(&0_f64),
+            recuperado,
+        })
+        .collect();
+    creditos.sort_by(|a, b| a.codigo.cmp(&b.codigo));
+
+    Some(Economia {
+        competencia,
+        pis,
+        cofins,
+        situacao: prescricao::Situacao::Recuperavel,
+        creditos,
+        divergente,
+        efd_contribuicoes,
+    })
+}
+
+// Compares the establishments of EFD Contribuições with the uploaded EFD ICMS/IPI files. Returns
+// None when the user chooses not to continue without the missing ones.
+fn cobertura(
+    console: &Rc<RefCell<Console>>,
+    efd_contribuicoes: &PathBuf,
+    efd_icms_ipi: &[(String, PathBuf)],
+    sem_efd_icms_ipi: &mut Option<bool>,
+) -> Option<()> {
+    let estabelecimentos = efd_contribuicoes::estabelecimentos(efd_contribuicoes);
+
+    let ausentes: Vec<String> = estabelecimentos
+        .iter()
+        .filter(|(cnpj, icms)| *icms && !efd_icms_ipi.iter().any(|(c, _)| c == cnpj))
+        .map(|(cnpj, _)| cnpj.clone())
+        .collect();
+    let estranhos: Vec<&PathBuf> = efd_icms_ipi
+        .iter()
+        .filter(|(cnpj, _)| !estabelecimentos.iter().any(|(c, _)| c == cnpj))
+        .map(|(_, path)| path)
+        .collect();
+
+    if ausentes.is_empty() && estranhos.is_empty() {
+        return Some(());
+    }
+
+    console
+        .borrow_mut()
+        .add_line("Verificação de cobertura".to_string());
+    for cnpj in ausentes.iter() {
         console
             .borrow_mut()
-            .add_line(format!("  Economia tributária (COFINS): {:.2}", economia));
+            .add_line(format!("- FILIAL: {}; EFD ICMS/IPI não apresentada", cnpj));
+    }
+    for path in estranhos {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        console.borrow_mut().add_line(format!(
+            "- EFD ICMS/IPI {} não pertence aos estabelecimentos da EFD Contribuições",
+            filename
+        ));
+    }
+    console.borrow_mut().add_line("\n".to_string());
+
+    if ausentes.is_empty() {
+        return Some(());
+    }
+
+    let continuar = *sem_efd_icms_ipi.get_or_insert_with(|| {
+        choice2_default(
+            "Há estabelecimentos sem EFD ICMS/IPI. Continuar a análise do lote sem os respectivos arquivos?",
+            "Continuar",
+            "Cancelar",
+            "",
+        ) == Some(0)
+    });
+
+    if continuar {
+        // C170, C175 and F100 with a configured rate carry their own ICMS; the registers that
+        // draw on the EFD ICMS/IPI totals get none for these establishments.
+        console.borrow_mut().add_line(format!(
+            "- Análise continuada sem a EFD ICMS/IPI de {} estabelecimento(s): considerado apenas o ICMS destacado em C170, C175 e F100 por alíquota",
+            ausentes.len()
+        ));
         console.borrow_mut().add_line("\n".to_string());
+        Some(())
+    } else {
+        console
+            .borrow_mut()
+            .add_line("- Análise da competência cancelada".to_string());
+        console.borrow_mut().add_line("\n".to_string());
+        None
     }
+}
 
+fn consolidar(console: &Rc<RefCell<Console>>, economias: &[Economia]) {
+    console
+        .borrow_mut()
+        .add_line("Economia tributária por competência...".to_string());
+    for economia in economias {
+        let periodo = periodo(&economia.competencia);
+        console.borrow_mut().add_line(format!(
+            "- {}/{}; PIS: {:.2}; COFINS: {:.2}; TOTAL: {:.2}; SITUAÇÃO: {}{}",
+            &periodo[4..],
+            &periodo[..4],
+            economia.pis,
+            economia.cofins,
+            economia.pis + economia.cofins,
+            economia.situacao,
+            if economia.divergente {
+                "; DCTF DIVERGENTE"
+            } else {
+                ""
+            }
+        ));
+    }
     console.borrow_mut().add_line("\n".to_string());
+
+    // Prescribed and modulated competências are listed above but left out of the totals.
+    let economias: Vec<&Economia> = economias
+        .iter()
+        .filter(|e| e.situacao.recuperavel())
+        .collect();
+
+    let mut anos: Vec<(String, f64, f64)> = Vec::new();
+    for economia in economias.iter() {
+        let ano = periodo(&economia.competencia)[..4].to_string();
+        match anos.last_mut() {
+            Some(last) if last.0 == ano => {
+                last.1 += economia.pis;
+                last.2 += economia.cofins;
+            }
+            _ => anos.push((ano, economia.pis, economia.cofins)),
+        }
+    }
+
     console
         .borrow_mut()
-        .add_line("Economia tributária total...".to_string());
+        .add_line("Economia tributária por ano...".to_string());
+    for (ano, pis, cofins) in anos.iter() {
+        console.borrow_mut().add_line(format!(
+            "- {}; PIS: {:.2}; COFINS: {:.2}; TOTAL: {:.2}",
+            ano,
+            pis,
+            cofins,
+            pis + cofins
+        ));
+    }
+    console.borrow_mut().add_line("\n".to_string());
+
+    let pis: f64 = economias.iter().fold(0_f64, |acc, e| acc + e.pis);
+    let cofins: f64 = economias.iter().fold(0_f64, |acc, e| acc + e.cofins);
+
+    console
+        .borrow_mut()
+        .add_line("Economia tributária de todas as competências...".to_string());
     console.borrow_mut().add_line(format!("- PIS: {:.2}", pis));
     console
         .borrow_mut()
         .add_line(format!("- COFINS: {:.2}", cofins));
 }
 
+// Corrects the savings of each recoverable competência by the SELIC, from the payment of the
+// contribution (the month after the competência) to the chosen month.
+fn atualizar(console: &Rc<RefCell<Console>>, economias: &[Economia], config: &config::Selic) {
+    let economias: Vec<&Economia> = economias
+        .iter()
+        .filter(|e| e.situacao.recuperavel())
+        .collect();
+    if economias.is_empty() {
+        return;
+    }
+
+    let tabela = selic::load(&config.tabela);
+
+    // As in the ledger, the correction stops at the month after the last rate of the table.
+    let fim = tabela.ultimo().expect("Tabela SELIC vazia.") + 1;
+    let pedida: u32 = match &config.data {
+        Some(data) => selic::mes(data).expect("Data de atualização SELIC inválida."),
+        None => fim,
+    };
+    let data = pedida.min(fim);
+
+    console.borrow_mut().add_line(format!(
+        "Atualização pela SELIC até {}...",
+        selic::mes_to_string(data)
+    ));
+    if pedida > fim {
+        console.borrow_mut().add_line(format!(
+            "- ATENÇÃO: a tabela SELIC termina em {}; a atualização até {} foi limitada a {}.",
+            selic::mes_to_string(fim - 1),
+            selic::mes_to_string(pedida),
+            selic::mes_to_string(fim)
+        ));
+    }
+
+    let mut nominal: f64 = 0_f64;
+    let mut corrigido: f64 = 0_f64;
+
+    for economia in economias {
+        let competencia = selic::mes(&economia.competencia).expect("Competência inválida.");
+        let taxa = tabela.acumulada(competencia + 1, data);
+
+        let valor = economia.pis + economia.cofins;
+        let valor_corrigido = valor * (1_f64 + taxa / 100_f64);
+
+        console.borrow_mut().add_line(format!(
+            "- {}; PAGAMENTO: {}; VALOR: {:.2}; SELIC: {:.2}%; CORRIGIDO: {:.2}",
+            selic::mes_to_string(competencia),
+            selic::mes_to_string(competencia + 1),
+            valor,
+            taxa,
+            valor_corrigido
+        ));
+
+        nominal += valor;
+        corrigido += valor_corrigido;
+    }
+
+    console.borrow_mut().add_line(format!(
+        "- TOTAL; VALOR: {:.2}; CORRIGIDO: {:.2}",
+        nominal, corrigido
+    ));
+    console.borrow_mut().add_line("\n".to_string());
+}
+
+// Folder of the files written for a company: the configured one or, by default, the folder of its
+// EFD Contribuições.
+fn pasta(configurada: Option<&PathBuf>, economias: &[Economia]) -> Option<PathBuf> {
+    match configurada {
+        Some(pasta) => Some(pasta.clone()),
+        None => economias
+            .first()
+            .and_then(|economia| economia.efd_contribuicoes.parent())
+            .map(PathBuf::from),
+    }
+}
+
+// Writes the recovered amounts of the recoverable competências of a company by revenue code, for
+// the PER/DCOMP.
+fn exportar(
+    console: &Rc<RefCell<Console>>,
+    empresa: &str,
+    economias: &[Economia],
+    config: &config::Perdcomp,
+) {
+    let creditos: Vec<&perdcomp::Credito> = economias
+        .iter()
+        .filter(|e| e.situacao.recuperavel())
+        .flat_map(|e| e.creditos.iter())
+        .collect();
+    if creditos.is_empty() {
+        return;
+    }
+
+    let pagamentos = config.darf.as_deref().map(perdcomp::pagamentos);
+
+    let Some(pasta) = pasta(config.pasta.as_ref(), economias) else {
+        return;
+    };
+    let path = pasta.join(format!("perdcomp-{}.csv", empresa));
+    perdcomp::exportar(&path, &creditos, pagamentos.as_ref());
+
+    console
+        .borrow_mut()
+        .add_line("Créditos por código de receita (PER/DCOMP)...".to_string());
+    console
+        .borrow_mut()
+        .add_line(format!("- Arquivo: {}", path.display()));
+    console.borrow_mut().add_line("\n".to_string());
+}
+
+// Records the credits of the recoverable competências in the ledger of the company, dropping the
+// ones of the analysed competências that are no longer recoverable, and shows its compensations
+// and balance.
+fn escriturar(
+    console: &Rc<RefCell<Console>>,
+    empresa: &str,
+    economias: &[Economia],
+    config: &config::Razao,
+    geral: &config::Config,
+) {
+    let Some(pasta) = pasta(config.pasta.as_ref(), economias) else {
+        return;
+    };
+    let path = pasta.join(format!("razao-{}.yaml", empresa));
+
+    let mut razao = razao::load(&path);
+    for economia in economias.iter() {
+        if economia.situacao.recuperavel() && (economia.pis > 0.005 || economia.cofins > 0.005) {
+            razao.registrar(&economia.competencia, economia.pis, economia.cofins);
+        } else {
+            razao.remover(&economia.competencia);
+        }
+    }
+    if razao.creditos.is_empty() && !path.exists() {
+        return;
+    }
+    razao.save(&path);
+
+    let tabela = geral.selic.as_ref().map(|selic| selic::load(&selic.tabela));
+
+    // Without a date, the balance is shown where the SELIC correction stops or, without the table,
+    // after the last compensation or credit.
+    let data: u32 = match (&config.data, &tabela) {
+        (Some(data), _) => selic::mes(data).expect("Data do razão de créditos inválida."),
+        (None, Some(tabela)) => tabela.ultimo().expect("Tabela SELIC vazia.") + 1,
+        (None, None) => razao
+            .compensacoes
+            .iter()
+            .filter_map(|compensacao| selic::mes(&compensacao.data))
+            .chain(
+                razao
+                    .creditos
+                    .iter()
+                    .filter_map(|credito| selic::mes(&credito.competencia))
+                    .map(|mes| mes + 1),
+            )
+            .max()
+            .unwrap_or_default(),
+    };
+
+    // Five years after the judgment became final.
+    let limite = geral
+        .acoes
+        .get(empresa)
+        .and_then(|acao| acao.transito_em_julgado.as_deref())
+        .map(|transito| {
+            let (ano, mes, dia) =
+                prescricao::data(transito).expect("Data do trânsito em julgado inválida.");
+            (ano + 5, mes, dia)
+        });
+
+    let extrato = razao.extrato(tabela.as_ref(), data, limite);
+
+    console
+        .borrow_mut()
+        .add_line(format!("Razão de créditos ({})...", path.display()));
+
+    for movimento in extrato.movimentos.iter() {
+        let mut linha = format!(
+            "- {}; DÉBITO: {}; VALOR: {:.2}; ORIGINAL: {:.2}; SALDO: {:.2}",
+            movimento.compensacao.data,
+            movimento.compensacao.debito,
+            movimento.compensacao.valor,
+            movimento.original,
+            movimento.saldo
+        );
+        if movimento.excedente > 0.005 {
+            linha.push_str(&format!("; SEM CRÉDITO: {:.2}", movimento.excedente));
+        }
+        if movimento.fora_do_prazo {
+            linha.push_str("; FORA DO PRAZO");
+        }
+        console.borrow_mut().add_line(linha);
+    }
+
+    // The SELIC correction stops at the month after the last rate of the table.
+    let fim: Option<u32> = tabela
+        .as_ref()
+        .and_then(selic::Tabela::ultimo)
+        .map(|ultimo| ultimo + 1);
+    let atualizacao = fim.map_or(data, |fim| data.min(fim));
+
+    console.borrow_mut().add_line(format!(
+        "- SALDO ORIGINAL: {:.2}; SALDO ATUALIZADO ATÉ {}: {:.2}",
+        extrato.saldo_original,
+        selic::mes_to_string(atualizacao),
+        extrato.saldo_atualizado
+    ));
+
+    if let Some(fim) = fim.filter(|fim| data > *fim) {
+        console.borrow_mut().add_line(format!(
+            "- ATENÇÃO: a tabela SELIC termina em {}; as compensações e o saldo posteriores são corrigidos só até {}.",
+            selic::mes_to_string(fim - 1),
+            selic::mes_to_string(fim)
+        ));
+    }
+
+    // Balance left when the five years are over or about to be.
+    if let (Some((ano, mes, dia)), true) = (limite, extrato.saldo_original > 0.005) {
+        console.borrow_mut().add_line(format!(
+            "- ATENÇÃO: o prazo para compensar o saldo termina em {:02}/{:02}/{}.",
+            dia, mes, ano
+        ));
+    }
+    console.borrow_mut().add_line("\n".to_string());
+}
+
+// ICMS of a CST, leaving out the registers split by rate in the item-level exclusion.
+fn vl_icms_cst(summary: &HashMap<(String, String, String), f64>, cst: &str, item: bool) -> f64 {
+    summary
+        .iter()
+        .filter(|(key, _)| key.2 == cst)
+        .filter(|(key, _)| !item || !efd_contribuicoes::REG_ITEM.contains(&key.1.as_str()))
+        .fold(0_f64, |acc, (_, &value)| acc + value)
+}
+
+// Share of each branch in the ICMS of a CST.
+fn filiais(summary: &HashMap<(String, String, String), f64>, cst: &str) -> Vec<(String, f64)> {
+    let vl_icms: f64 = vl_icms_cst(summary, cst, false);
+
+    let mut filiais: HashMap<String, f64> = HashMap::new();
+    if vl_icms > 0_f64 {
+        for (key, value) in summary.iter().filter(|(key, _)| key.2 == cst) {
+            *filiais.entry(key.0.clone()).or_insert(0_f64) += value / vl_icms;
+        }
+    }
+
+    let mut filiais: Vec<(String, f64)> = filiais.into_iter().collect();
+    filiais.sort_by(|a, b| a.0.cmp(&b.0));
+    filiais
+}
+
+// ICMS to exclude from each line of a regime and CST. The ICMS that isn't split by rate goes to
+// the regime by `proporcao_regime`, and then to each line by its share of the gross revenue of
+// the regime. In the item-level exclusion, each line also gets the ICMS of the items with its CST
+// and rate, shared with the lines of the other regime that have the same CST and rate. Items
+// without such a line are allocated in proportion.
+fn exclusao(
+    todas: &[efd_contribuicoes::Apuracao],
+    apuracoes: &[&efd_contribuicoes::Apuracao],
+    cst: &str,
+    vl_icms: f64,
+    proporcao_regime: f64,
+    itens: &HashMap<(String, String), f64>,
+) -> Vec<f64> {
+    // Gross revenue of the lines with the CST and a rate, or None when there are no such lines.
+    let vl_rec_brt_aliq = |aliq: &str| -> Option<f64> {
+        let linhas: Vec<&efd_contribuicoes::Apuracao> = todas
+            .iter()
+            .filter(|apuracao| {
+                apuracao.cst() == cst && efd_contribuicoes::aliquota(apuracao.aliq_cont) == aliq
+            })
+            .collect();
+
+        if linhas.is_empty() {
+            None
+        } else {
+            Some(
+                linhas
+                    .iter()
+                    .fold(0_f64, |acc, apuracao| acc + apuracao.vl_rec_brt),
+            )
+        }
+    };
+
+    let sem_linha: f64 = itens
+        .iter()
+        .filter(|((c, aliq), _)| c == cst && vl_rec_brt_aliq(aliq).is_none())
+        .fold(0_f64, |acc, (_, &value)| acc + value);
+
+    let vl_icms_regime = (vl_icms + sem_linha) * proporcao_regime;
+    let vl_rec_brt: f64 = apuracoes
+        .iter()
+        .fold(0_f64, |acc, apuracao| acc + apuracao.vl_rec_brt);
+
+    apuracoes
+        .iter()
+        .map(|apuracao| {
+            let proporcional = if vl_rec_brt > 0_f64 {
+                vl_icms_regime * apuracao.vl_rec_brt / vl_rec_brt
+            } else {
+                0_f64
+            };
+
+            let aliq = efd_contribuicoes::aliquota(apuracao.aliq_cont);
+            let vl_icms_aliq = *itens
+                .get(&(cst.to_string(), aliq.clone()))
+                .unwrap_or(&0_f64);
+            let por_item = match vl_rec_brt_aliq(&aliq) {
+                Some(vl_rec_brt_aliq) if vl_rec_brt_aliq > 0_f64 => {
+                    vl_icms_aliq * apuracao.vl_rec_brt / vl_rec_brt_aliq
+                }
+                _ => 0_f64,
+            };
+
+            proporcional + por_item
+        })
+        .collect()
+}
+
+// ICMS the taxpayer already excluded from each line: its M215/M615 adjustments, and the share of
+// the C170 items with a reduced base, allocated like the itemized ICMS.
+fn ja_excluido(
+    todas: &[efd_contribuicoes::Apuracao],
+    apuracoes: &[&efd_contribuicoes::Apuracao],
+    cst: &str,
+    proporcao_regime: f64,
+    excluido: &HashMap<(String, String), f64>,
+) -> Vec<f64> {
+    exclusao(todas, apuracoes, cst, 0_f64, proporcao_regime, excluido)
+        .into_iter()
+        .zip(apuracoes)
+        .map(|(vl_icms, apuracao)| vl_icms + apuracao.vl_ajus_icms)
+        .collect()
+}
+
+// Only the ICMS not yet excluded is claimed.
+fn restante(vl_icms: &[f64], excluido: &[f64]) -> Vec<f64> {
+    vl_icms
+        .iter()
+        .zip(excluido)
+        .map(|(vl_icms, excluido)| (vl_icms - excluido).max(0_f64))
+        .collect()
+}
+
+fn apurar(
+    console: &Rc<RefCell<Console>>,
+    registro: &str,
+    tributo: &str,
+    cst: &str,
+    apuracoes: &[&efd_contribuicoes::Apuracao],
+    vl_icms: &[f64],
+    excluido: &[f64],
+) -> f64 {
+    let mut total: f64 = 0_f64;
+
+    console
+        .borrow_mut()
+        .add_line(format!("Analisando registro {} (CST {})...", registro, cst));
+    for ((i, &icms), &excluido) in apuracoes.iter().zip(vl_icms).zip(excluido) {
+        console
+            .borrow_mut()
+            .add_line(format!("- Base de cálculo original: {:.2}", i.vl_bc_cont));
+        if excluido > 0_f64 {
+            console.borrow_mut().add_line(format!(
+                "  ICMS já excluído pelo contribuinte: {:.2}",
+                excluido
+            ));
+        }
+        console
+            .borrow_mut()
+            .add_line(format!("  ICMS a ser excluído: {:.2}", icms));
+        let economia = (icms * i.aliq_cont) / 100_f64;
+        total += economia;
+        console.borrow_mut().add_line(format!(
+            "  Economia tributária ({}): {:.2}",
+            tributo, economia
+        ));
+        console.borrow_mut().add_line("\n".to_string());
+    }
+
+    total
+}
+
 fn main() {
     let app = App::default();
     let mut wind = Window::new(100, 100, 1400, 700, "Tema 69 STF");
 
     let console = Rc::new(RefCell::new(Console::new()));
+    let config = config::load(Path::new("tema-69-stf.yaml")).unwrap_or_else(|erro| {
+        alert_default(&erro);
+        config::Config::default()
+    });
 
     let mut upload_button = Button::new(1190, 650, 200, 40, "Adicionar Arquivo");
     upload_button.set_callback(move |_| {
         let mut dialog = FileDialog::new(FileDialogType::BrowseMultiFile);
         dialog.show();
 
-        process_files(console.clone(), dialog.filenames());
+        process_files(console.clone(), dialog.filenames(), &config);
     });
 
     wind.end();
@@ -321,3 +1697,227 @@ fn main() {
 
     app.run().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apuracao(cod_cont: &str, vl_rec_brt: f64, aliq_cont: f64) -> efd_contribuicoes::Apuracao {
+        efd_contribuicoes::Apuracao {
+            cod_cont: cod_cont.to_string(),
+            vl_rec_brt,
+            vl_bc_cont: vl_rec_brt,
+            aliq_cont,
+            linha: String::new(),
+            numero: 0,
+            vl_ajus_icms: 0_f64,
+        }
+    }
+
+    fn perto(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // An EFD Contribuições of 01/2023 with its TIPO_ESCRIT and NUM_REC_ANTERIOR, and the lines
+    // that tell copies apart.
+    fn efd(nome: &str, tipo_escrit: &str, num_rec_anterior: &str, corpo: &str) -> (String, String) {
+        (
+            nome.to_string(),
+            format!(
+                "|0000|006|{}|0|{}|01012023|31012023|EMPRESA|11222333000181|SP|3550308||00|2|\r\n{}",
+                tipo_escrit, num_rec_anterior, corpo
+            ),
+        )
+    }
+
+    fn agrupar(arquivos: &[(String, String)], recibos: &[(&str, &str)]) -> Sped {
+        let recibos: HashMap<String, String> = recibos
+            .iter()
+            .map(|(nome, numero)| (nome.to_string(), numero.to_string()))
+            .collect();
+        let arquivos: Vec<Arquivo> = arquivos
+            .iter()
+            .map(|(nome, texto)| Arquivo::new(Path::new(nome), texto.as_bytes(), &recibos).unwrap())
+            .collect();
+
+        let (mut speds, nao_atribuidos) = Sped::agrupar(arquivos, Vec::new());
+        assert!(nao_atribuidos.is_empty());
+        assert_eq!(speds.len(), 1);
+        speds.pop().unwrap()
+    }
+
+    fn substituidos(sped: &Sped) -> Vec<(String, String)> {
+        sped.substituidos
+            .iter()
+            .map(|(path, motivo)| (path.display().to_string(), motivo.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn retificadora_prevalece_sobre_a_original() {
+        let sped = agrupar(
+            &[
+                efd("original.txt", "0", "", "|9999|1|"),
+                efd("retificadora.txt", "1", "", "|9999|2|"),
+            ],
+            &[],
+        );
+
+        assert_eq!(
+            sped.efd_contribuicoes,
+            Some(PathBuf::from("retificadora.txt"))
+        );
+        let substituidos = substituidos(&sped);
+        assert_eq!(substituidos.len(), 1);
+        assert_eq!(substituidos[0].0, "original.txt");
+        assert!(substituidos[0].1.contains("sem recibo"));
+    }
+
+    #[test]
+    fn retificadoras_encadeadas_pelo_recibo() {
+        // The last retifying file has the smallest name, so only the receipts find it.
+        let sped = agrupar(
+            &[
+                efd("c_original.txt", "0", "", "|9999|1|"),
+                efd("b_retificadora.txt", "1", "1111", "|9999|2|"),
+                efd("a_retificadora.txt", "1", "2222", "|9999|3|"),
+            ],
+            &[("c_original.txt", "1111"), ("b_retificadora.txt", "2222")],
+        );
+
+        assert_eq!(
+            sped.efd_contribuicoes,
+            Some(PathBuf::from("a_retificadora.txt"))
+        );
+        assert_eq!(
+            substituidos(&sped),
+            [
+                (
+                    "b_retificadora.txt".to_string(),
+                    "retificado por a_retificadora.txt (NUM_REC_ANTERIOR 2222)".to_string()
+                ),
+                (
+                    "c_original.txt".to_string(),
+                    "retificado por b_retificadora.txt (NUM_REC_ANTERIOR 1111)".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn copia_identica_e_descartada() {
+        let original = efd("original.txt", "0", "", "|9999|1|");
+        let copia = ("copia.txt".to_string(), original.1.clone());
+
+        let sped = agrupar(&[original, copia], &[]);
+
+        assert_eq!(sped.efd_contribuicoes, Some(PathBuf::from("copia.txt")));
+        assert_eq!(
+            substituidos(&sped),
+            [(
+                "original.txt".to_string(),
+                "duplicado de copia.txt".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn recibo_ausente_adota_a_retificadora_de_maior_nome() {
+        // The receipt of the first retifying file isn't in `recibos`, so the second can't be
+        // chained to it.
+        let sped = agrupar(
+            &[
+                efd("a_original.txt", "0", "", "|9999|1|"),
+                efd("b_retificadora.txt", "1", "1111", "|9999|2|"),
+                efd("c_retificadora.txt", "1", "2222", "|9999|3|"),
+            ],
+            &[("a_original.txt", "1111")],
+        );
+
+        assert_eq!(
+            sped.efd_contribuicoes,
+            Some(PathBuf::from("c_retificadora.txt"))
+        );
+        let substituidos = substituidos(&sped);
+        assert_eq!(substituidos.len(), 2);
+        assert_eq!(
+            substituidos[0],
+            (
+                "a_original.txt".to_string(),
+                "retificado por b_retificadora.txt (NUM_REC_ANTERIOR 1111)".to_string()
+            )
+        );
+        assert_eq!(substituidos[1].0, "b_retificadora.txt");
+        assert!(substituidos[1]
+            .1
+            .starts_with("substituído por c_retificadora.txt"));
+    }
+
+    #[test]
+    fn exclusao_proporcional_a_receita() {
+        let todas = vec![
+            apuracao("01", 3000_f64, 1.65),
+            apuracao("01", 1000_f64, 0.65),
+        ];
+        let apuracoes: Vec<&efd_contribuicoes::Apuracao> = todas.iter().collect();
+
+        let icms = exclusao(&todas, &apuracoes, "01", 400_f64, 1_f64, &HashMap::new());
+
+        assert!(perto(icms[0], 300_f64));
+        assert!(perto(icms[1], 100_f64));
+    }
+
+    #[test]
+    fn exclusao_divide_entre_regimes() {
+        let todas = vec![
+            apuracao("01", 3000_f64, 1.65),
+            apuracao("51", 1000_f64, 0.65),
+        ];
+        let nao_cumulativo: Vec<&efd_contribuicoes::Apuracao> = vec![&todas[0]];
+
+        let icms = exclusao(
+            &todas,
+            &nao_cumulativo,
+            "01",
+            400_f64,
+            0.75,
+            &HashMap::new(),
+        );
+
+        assert_eq!(icms.len(), 1);
+        assert!(perto(icms[0], 300_f64));
+    }
+
+    #[test]
+    fn exclusao_por_item_segue_a_aliquota() {
+        let todas = vec![
+            apuracao("01", 3000_f64, 1.65),
+            apuracao("01", 1000_f64, 0.65),
+        ];
+        let apuracoes: Vec<&efd_contribuicoes::Apuracao> = todas.iter().collect();
+
+        // 100 of items at 0,65%, and 40 at a rate without a line, which is allocated in
+        // proportion with the ICMS that isn't split by rate.
+        let itens = HashMap::from([
+            (
+                ("01".to_string(), efd_contribuicoes::aliquota(0.65)),
+                100_f64,
+            ),
+            (("01".to_string(), efd_contribuicoes::aliquota(3.0)), 40_f64),
+        ]);
+        let icms = exclusao(&todas, &apuracoes, "01", 160_f64, 1_f64, &itens);
+
+        assert!(perto(icms[0], 150_f64));
+        assert!(perto(icms[1], 50_f64 + 100_f64));
+    }
+
+    #[test]
+    fn exclusao_sem_receita_nao_divide_por_zero() {
+        let todas = vec![apuracao("01", 0_f64, 1.65)];
+        let apuracoes: Vec<&efd_contribuicoes::Apuracao> = todas.iter().collect();
+
+        let icms = exclusao(&todas, &apuracoes, "01", 100_f64, 1_f64, &HashMap::new());
+
+        assert!(perto(icms[0], 0_f64));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config;
+
+use std::collections::HashMap;
+
+// NCM prefixes of products under the monophasic regime, where the reseller's rate is zero.
+
+// Lei 9.718/1998, Lei 10.560/2002 e Lei 11.116/2005
+const COMBUSTIVEIS: &[&str] = &[
+    "271012", "271019", "271020", "271112", "271113", "271119", "220710", "220720", "38260000",
+];
+
+// Lei 10.147/2000
+const FARMACEUTICOS: &[&str] = &[
+    "3001", "300210", "300220", "300290", "3003", "3004", "300510", "300630", "300660",
+];
+
+// Lei 10.147/2000
+const HIGIENE_PESSOAL: &[&str] = &[
+    "3303", "3304", "3305", "330710", "330720", "34011190", "34012010", "96032100",
+];
+
+// Lei 10.485/2002
+const VEICULOS: &[&str] = &[
+    "8429", "843240", "843280", "843320", "843330", "843340", "84335", "8701", "8702", "8703",
+    "8704", "8705", "8706", "8711", "4011", "4013",
+];
+
+// Lei 13.097/2015
+const BEBIDAS_FRIAS: &[&str] = &[
+    "2106901", "2201", "2202", "2203", "220410", "220421", "220429", "2206",
+];
+
+const NCM: [&[&str]; 5] = [
+    COMBUSTIVEIS,
+    FARMACEUTICOS,
+    HIGIENE_PESSOAL,
+    VEICULOS,
+    BEBIDAS_FRIAS,
+];
+
+pub struct Item {
+    pub(crate) cnpj: String,
+    pub(crate) reg: String,
+    pub(crate) cod_item: String,
+    pub(crate) ncm: String,
+    pub(crate) vl_opr: f64,
+}
+
+// Flags CST 01 revenue of items whose NCM is monophasic, and reclassifies it to CST 04 when
+// configured to.
+pub(crate) struct Classificador<'a> {
+    // NCM of each (cnpj, cod_item), as item codes are only unique within an establishment.
+    produtos: HashMap<(String, String), String>,
+    config: &'a config::Monofasico,
+    itens: HashMap<(String, String, String), Item>,
+}
+
+impl<'a> Classificador<'a> {
+    pub(crate) fn new(
+        produtos: HashMap<(String, String), String>,
+        config: &'a config::Monofasico,
+    ) -> Self {
+        Self {
+            produtos,
+            config,
+            itens: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn add_produto(&mut self, cnpj: String, cod_item: String, ncm: String) {
+        self.produtos.insert((cnpj, cod_item), ncm);
+    }
+
+    fn monofasico(&self, cnpj: &str, cod_item: &str) -> Option<&String> {
+        self.produtos
+            .get(&(cnpj.to_string(), cod_item.to_string()))
+            .filter(|ncm| is_monofasico(ncm, self.config))
+    }
+
+    pub(crate) fn cst<'b>(
+        &mut self,
+        reg: &str,
+        cnpj: &str,
+        cod_item: &str,
+        cst: &'b str,
+        vl_opr: f64,
+    ) -> &'b str {
+        if cst != "01" {
+            return cst;
+        }
+
+        let ncm = match self.monofasico(cnpj, cod_item) {
+            Some(ncm) => ncm.clone(),
+            None => return cst,
+        };
+
+        let key = (cnpj.to_string(), reg.to_string(), cod_item.to_string());
+        let item = self.itens.entry(key).or_insert(Item {
+            cnpj: cnpj.to_string(),
+            reg: reg.to_string(),
+            cod_item: cod_item.to_string(),
+            ncm,
+            vl_opr: 0_f64,
+        });
+        item.vl_opr += vl_opr;
+
+        if self.config.reclassificar {
+            "04"
+        } else {
+            cst
+        }
+    }
+
+    // CST of an item, without reporting it, for registers that repeat the items of another.
+    pub(crate) fn reclassificar<'b>(&self, cnpj: &str, cod_item: &str, cst: &'b str) -> &'b str {
+        let monofasico = self.monofasico(cnpj, cod_item).is_some();
+
+        if cst == "01" && monofasico && self.config.reclassificar {
+            "04"
+        } else {
+            cst
+        }
+    }
+
+    pub(crate) fn itens(self) -> Vec<Item> {
+        let mut itens: Vec<Item> = self.itens.into_values().collect();
+        itens.sort_by(|a, b| (&a.cnpj, &a.reg, &a.cod_item).cmp(&(&b.cnpj, &b.reg, &b.cod_item)));
+        itens
+    }
+}
+
+pub(crate) fn is_monofasico(ncm: &str, config: &config::Monofasico) -> bool {
+    NCM.iter()
+        .flat_map(|ncm| ncm.iter().copied())
+        .chain(config.ncm.iter().map(String::as_str))
+        .any(|prefixo| !prefixo.is_empty() && ncm.starts_with(prefixo))
+}
@@ -0,0 +1,178 @@
+use encoding_rs::*;
+
+use crate::ajuste::valor;
+use crate::efd_contribuicoes::decimal;
+use crate::selic::mes;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Recovered amount of a competência (DT_FIN) and revenue code, with the debt declared for that
+// code in M205/M605.
+pub(crate) struct Credito {
+    pub(crate) competencia: String,
+    pub(crate) codigo: String,
+    pub(crate) debito: f64,
+    pub(crate) recuperado: f64,
+}
+
+// DARF revenue codes of PIS and COFINS by regime.
+pub(crate) fn codigo(tributo: &str, cumulativo: bool) -> &'static str {
+    match (tributo, cumulativo) {
+        ("PIS", false) => "6912",
+        ("PIS", true) => "8109",
+        ("COFINS", false) => "5856",
+        _ => "2172",
+    }
+}
+
+fn tributo(codigo: &str) -> &'static str {
+    match codigo {
+        "6912" | "8109" => "PIS",
+        _ => "COFINS",
+    }
+}
+
+// DARF payments from a CSV with "dd/mm/aaaa;código;valor" lines, by payment month and revenue code
+// (the first four digits, without the variation).
+pub(crate) fn pagamentos(path: &Path) -> HashMap<(u32, String), f64> {
+    let text = fs::read_to_string(path).expect("Não foi possível ler o arquivo de DARF.");
+
+    ler_pagamentos(&text)
+}
+
+fn ler_pagamentos(text: &str) -> HashMap<(u32, String), f64> {
+    let mut pagamentos: HashMap<(u32, String), f64> = HashMap::new();
+
+    for line in text.lines() {
+        let data: Vec<&str> = line.split(';').map(str::trim).collect();
+
+        let (Some(mes), Some(codigo), Some(valor)) = (
+            data.first().and_then(|value| mes(value)),
+            data.get(1).and_then(|value| value.get(..4)),
+            data.get(2).and_then(|value| decimal(value)),
+        ) else {
+            continue;
+        };
+
+        *pagamentos.entry((mes, codigo.to_string())).or_insert(0_f64) += valor;
+    }
+
+    pagamentos
+}
+
+// CSV for the PER/DCOMP, one line per competência and revenue code with an amount to recover. With the DARF payments, each
+// line also shows what was paid in the month the code was due, what was due after the exclusion,
+// and the difference.
+pub(crate) fn exportar(
+    path: &Path,
+    creditos: &[&Credito],
+    pagamentos: Option<&HashMap<(u32, String), f64>>,
+) {
+    // In Windows-1252, as spreadsheets expect in Brazil.
+    let mut text = linhas(creditos, pagamentos).join("\r\n");
+    text.push_str("\r\n");
+    let (bytes, _, _) = WINDOWS_1252.encode(&text);
+    fs::write(path, bytes).expect("Não foi possível gravar o arquivo do PER/DCOMP.");
+}
+
+fn linhas(creditos: &[&Credito], pagamentos: Option<&HashMap<(u32, String), f64>>) -> Vec<String> {
+    let mut linhas: Vec<String> = vec![
+        "Competência;Tributo;Código;Débito declarado;Valor recuperado;Pago;Devido;Pago a maior"
+            .to_string(),
+    ];
+
+    for credito in creditos.iter().filter(|credito| credito.recuperado > 0.005) {
+        let competencia = mes(&credito.competencia).expect("Competência inválida.");
+
+        let mut linha = format!(
+            "{:02}/{};{};{};{};{}",
+            competencia % 12 + 1,
+            competencia / 12,
+            tributo(&credito.codigo),
+            credito.codigo,
+            valor(credito.debito),
+            valor(credito.recuperado)
+        );
+
+        if let Some(pagamentos) = pagamentos {
+            // Due on the 25th of the month after the competência.
+            let pago = *pagamentos
+                .get(&(competencia + 1, credito.codigo.clone()))
+                .unwrap_or(&0_f64);
+            let devido = (credito.debito - credito.recuperado).max(0_f64);
+
+            linha.push_str(&format!(
+                ";{};{};{}",
+                valor(pago),
+                valor(devido),
+                valor((pago - devido).max(0_f64))
+            ));
+        } else {
+            linha.push_str(";;;");
+        }
+
+        linhas.push(linha);
+    }
+
+    linhas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credito(codigo: &str, debito: f64, recuperado: f64) -> Credito {
+        Credito {
+            competencia: "31012023".to_string(),
+            codigo: codigo.to_string(),
+            debito,
+            recuperado,
+        }
+    }
+
+    #[test]
+    fn pagamentos_somam_por_mes_e_codigo() {
+        let pagamentos = ler_pagamentos(
+            "Data;Código;Valor\n24/02/2023;6912-01;1.000,00\n27/02/2023;6912;650,00\n24/02/2023;5856;7600.00\n",
+        );
+
+        let fevereiro = mes("02/2023").unwrap();
+        assert_eq!(pagamentos.len(), 2);
+        assert!((pagamentos[&(fevereiro, "6912".to_string())] - 1650_f64).abs() < 1e-9);
+        assert!((pagamentos[&(fevereiro, "5856".to_string())] - 7600_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn linhas_confrontam_o_pago_no_vencimento() {
+        let pis = credito("6912", 1650_f64, 297_f64);
+        let cofins = credito("5856", 7600_f64, 1368_f64);
+        let pagamentos = HashMap::from([((mes("02/2023").unwrap(), "6912".to_string()), 1650_f64)]);
+
+        let linhas = linhas(&[&pis, &cofins], Some(&pagamentos));
+
+        assert_eq!(linhas.len(), 3);
+        assert_eq!(
+            linhas[1],
+            "01/2023;PIS;6912;1650,00;297,00;1650,00;1353,00;297,00"
+        );
+        // Nothing paid for COFINS in the month it was due.
+        assert_eq!(
+            linhas[2],
+            "01/2023;COFINS;5856;7600,00;1368,00;0,00;6232,00;0,00"
+        );
+    }
+
+    #[test]
+    fn linhas_sem_valor_a_recuperar_ficam_de_fora() {
+        let pis = credito("6912", 1650_f64, -12_f64);
+        let cofins = credito("5856", 7600_f64, 0_f64);
+        let cumulativo = credito("8109", 650_f64, 117_f64);
+
+        let linhas = linhas(&[&pis, &cofins, &cumulativo], None);
+
+        assert_eq!(linhas.len(), 2);
+        assert_eq!(linhas[1], "01/2023;PIS;8109;650,00;117,00;;;");
+    }
+}
@@ -0,0 +1,144 @@
+use crate::config;
+
+use std::fmt;
+
+// Date from which the STF modulated the effects of Tema 69 (RE 574.706), except for lawsuits and
+// administrative claims filed until then.
+const MODULACAO: (u32, u32, u32) = (2017, 3, 15);
+
+// PIS/COFINS are due on the 25th of the month after the competência.
+const VENCIMENTO: u32 = 25;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Situacao {
+    Recuperavel,
+    // 03/2017 in a claim filed after the modulation: only the revenue from 15/03/2017 is
+    // recoverable, which the monthly apuração can't tell apart, so the month is left to review.
+    Parcial,
+    Prescrito,
+    Modulado,
+}
+
+impl Situacao {
+    // Competências that enter the totals, the SELIC correction, the PER/DCOMP and the ledger.
+    pub(crate) fn recuperavel(&self) -> bool {
+        *self == Situacao::Recuperavel
+    }
+}
+
+impl fmt::Display for Situacao {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Situacao::Recuperavel => write!(f, "recuperável"),
+            Situacao::Parcial => write!(
+                f,
+                "parcialmente modulado (recuperável apenas a partir de 15/03/2017; fora dos totais, revisar o valor)"
+            ),
+            Situacao::Prescrito => write!(f, "prescrito"),
+            Situacao::Modulado => write!(f, "modulado"),
+        }
+    }
+}
+
+// Date as (aaaa, mm, dd), from "dd/mm/aaaa" or DT_FIN (ddmmaaaa).
+pub(crate) fn data(text: &str) -> Option<(u32, u32, u32)> {
+    let text: String = text.trim().chars().filter(|c| *c != '/').collect();
+    if text.len() != 8 {
+        return None;
+    }
+
+    let dia: u32 = text[..2].parse().ok()?;
+    let mes: u32 = text[2..4].parse().ok()?;
+    let ano: u32 = text[4..].parse().ok()?;
+
+    Some((ano, mes, dia))
+}
+
+// A competência is prescribed when the contribution was paid more than five years before the
+// claim was filed (CTN, art. 168), and modulated when it ended before 15/03/2017 and the claim
+// was filed after that. A competência that is both is reported as prescribed, and the one that
+// contains 15/03/2017 is partially modulated.
+pub(crate) fn situacao(competencia: &str, acao: &config::Acao) -> Situacao {
+    let dt_fin = data(competencia).expect("Competência inválida.");
+    let ajuizamento = data(&acao.ajuizamento).expect("Data de ajuizamento inválida.");
+
+    let pagamento = if dt_fin.1 == 12 {
+        (dt_fin.0 + 1, 1, VENCIMENTO)
+    } else {
+        (dt_fin.0, dt_fin.1 + 1, VENCIMENTO)
+    };
+    let limite = (ajuizamento.0 - 5, ajuizamento.1, ajuizamento.2);
+
+    let anterior_modulacao = acao.anterior_modulacao.unwrap_or(ajuizamento <= MODULACAO);
+
+    if pagamento < limite {
+        Situacao::Prescrito
+    } else if dt_fin < MODULACAO && !anterior_modulacao {
+        Situacao::Modulado
+    } else if (dt_fin.0, dt_fin.1, 1) < MODULACAO && !anterior_modulacao {
+        Situacao::Parcial
+    } else {
+        Situacao::Recuperavel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acao(ajuizamento: &str, anterior_modulacao: Option<bool>) -> config::Acao {
+        config::Acao {
+            ajuizamento: ajuizamento.to_string(),
+            anterior_modulacao,
+            transito_em_julgado: None,
+        }
+    }
+
+    #[test]
+    fn data_aceita_barras_e_dt_fin() {
+        assert_eq!(data("15/03/2017"), Some((2017, 3, 15)));
+        assert_eq!(data("31012023"), Some((2023, 1, 31)));
+        assert_eq!(data("2023-01"), None);
+        assert_eq!(data("aa/bb/cccc"), None);
+    }
+
+    #[test]
+    fn modulacao_separa_o_mes_de_marco_de_2017() {
+        let acao = acao("10/05/2022", None);
+
+        assert_eq!(situacao("28022017", &acao), Situacao::Prescrito);
+
+        let acao = config::Acao {
+            ajuizamento: "10/05/2020".to_string(),
+            ..acao
+        };
+        assert_eq!(situacao("28022017", &acao), Situacao::Modulado);
+        assert_eq!(situacao("31032017", &acao), Situacao::Parcial);
+        assert_eq!(situacao("30042017", &acao), Situacao::Recuperavel);
+        assert!(!Situacao::Parcial.recuperavel());
+    }
+
+    #[test]
+    fn acao_anterior_a_modulacao_recupera_marco_inteiro() {
+        let acao = acao("14/03/2017", None);
+
+        assert_eq!(situacao("31032017", &acao), Situacao::Recuperavel);
+        assert_eq!(situacao("31012013", &acao), Situacao::Recuperavel);
+    }
+
+    #[test]
+    fn prescricao_conta_do_pagamento() {
+        // 04/2017 is paid on 25/05/2017, five years before a claim filed on 25/05/2022.
+        let acao = acao("25/05/2022", Some(true));
+
+        assert_eq!(situacao("30042017", &acao), Situacao::Recuperavel);
+        assert_eq!(situacao("31032017", &acao), Situacao::Prescrito);
+
+        // December is paid in January of the next year.
+        let acao = config::Acao {
+            ajuizamento: "25/01/2023".to_string(),
+            ..acao
+        };
+        assert_eq!(situacao("31122017", &acao), Situacao::Recuperavel);
+    }
+}
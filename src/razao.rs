@@ -0,0 +1,312 @@
+use serde::{Deserialize, Serialize};
+
+use crate::prescricao;
+use crate::selic;
+
+use std::fs::{self, File};
+use std::path::Path;
+
+// Credit utilization ledger of a company, kept in a YAML file. The credits come from the analysis
+// of each competência; the compensations are entered by hand.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Razao {
+    #[serde(default)]
+    pub(crate) creditos: Vec<Credito>,
+
+    #[serde(default)]
+    pub(crate) compensacoes: Vec<Compensacao>,
+}
+
+// Qualified credit of a competência ("mm/aaaa"), at its original value.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Credito {
+    pub(crate) competencia: String,
+    pub(crate) pis: f64,
+    pub(crate) cofins: f64,
+}
+
+// Compensation of a debt ("IRPJ 2089 - 03/2025", for instance) on a date ("dd/mm/aaaa").
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Compensacao {
+    pub(crate) data: String,
+    pub(crate) debito: String,
+    pub(crate) valor: f64,
+}
+
+// A compensation as applied to the credits, with the original value it used up.
+pub(crate) struct Movimento<'a> {
+    pub(crate) compensacao: &'a Compensacao,
+    pub(crate) original: f64,
+    pub(crate) saldo: f64,
+    // Amount left uncovered when the credits run out.
+    pub(crate) excedente: f64,
+    pub(crate) fora_do_prazo: bool,
+}
+
+pub(crate) struct Extrato<'a> {
+    pub(crate) movimentos: Vec<Movimento<'a>>,
+    pub(crate) saldo_original: f64,
+    pub(crate) saldo_atualizado: f64,
+}
+
+pub(crate) fn load(path: &Path) -> Razao {
+    match File::open(path) {
+        Ok(file) => serde_yaml::from_reader(file).expect("Razão de créditos inválido."),
+        Err(_) => Razao::default(),
+    }
+}
+
+// Month of a compensation, as in `selic::mes`.
+fn mes(compensacao: &Compensacao) -> u32 {
+    selic::mes(&compensacao.data).expect("Data de compensação inválida.")
+}
+
+impl Razao {
+    pub(crate) fn save(&self, path: &Path) {
+        let text = serde_yaml::to_string(self).expect("Razão de créditos inválido.");
+        fs::write(path, text).expect("Não foi possível gravar o razão de créditos.");
+    }
+
+    // Records the credit of a competência (DT_FIN), replacing the one of a previous analysis.
+    pub(crate) fn registrar(&mut self, competencia: &str, pis: f64, cofins: f64) {
+        let competencia =
+            selic::mes_to_string(selic::mes(competencia).expect("Competência inválida."));
+
+        match self
+            .creditos
+            .iter_mut()
+            .find(|credito| credito.competencia == competencia)
+        {
+            Some(credito) => {
+                credito.pis = pis;
+                credito.cofins = cofins;
+            }
+            None => self.creditos.push(Credito {
+                competencia,
+                pis,
+                cofins,
+            }),
+        }
+
+        self.creditos
+            .sort_by_key(|credito| selic::mes(&credito.competencia));
+    }
+
+    // Drops the credit of a competência (DT_FIN) that is no longer recoverable.
+    pub(crate) fn remover(&mut self, competencia: &str) {
+        let competencia =
+            selic::mes_to_string(selic::mes(competencia).expect("Competência inválida."));
+
+        self.creditos
+            .retain(|credito| credito.competencia != competencia);
+    }
+
+    // Applies the compensations up to `data` (a month, as in `selic::mes`) to the credits, oldest
+    // first. Each compensation uses up the original value that, corrected by the SELIC from the
+    // payment of the credit to the compensation, equals its amount; credits paid after the
+    // compensation aren't used. The correction stops at the month after the last rate of the table.
+    // Compensations after `limite`, five years after the judgment became final, are flagged.
+    pub(crate) fn extrato(
+        &self,
+        tabela: Option<&selic::Tabela>,
+        data: u32,
+        limite: Option<(u32, u32, u32)>,
+    ) -> Extrato<'_> {
+        let fim: Option<u32> = tabela
+            .and_then(selic::Tabela::ultimo)
+            .map(|ultimo| ultimo + 1);
+        let taxa = |pagamento: u32, mes: u32| {
+            tabela.map_or(0_f64, |tabela| {
+                tabela.acumulada(pagamento, fim.map_or(mes, |fim| mes.min(fim)))
+            })
+        };
+
+        // Original balance and payment month (the month after the competência) of each credit.
+        let mut saldos: Vec<(f64, u32)> = self
+            .creditos
+            .iter()
+            .map(|credito| {
+                let competencia = selic::mes(&credito.competencia).expect("Competência inválida.");
+                (credito.pis + credito.cofins, competencia + 1)
+            })
+            .collect();
+
+        let mut compensacoes: Vec<&Compensacao> = self
+            .compensacoes
+            .iter()
+            .filter(|compensacao| mes(compensacao) <= data)
+            .collect();
+        compensacoes.sort_by_key(|compensacao| {
+            prescricao::data(&compensacao.data).expect("Data de compensação inválida.")
+        });
+
+        let mut movimentos: Vec<Movimento> = Vec::new();
+
+        for compensacao in compensacoes {
+            let mes_compensacao = mes(compensacao);
+
+            let mut restante = compensacao.valor;
+            let mut original = 0_f64;
+
+            for (saldo, pagamento) in saldos.iter_mut() {
+                if restante <= 0_f64 {
+                    break;
+                }
+                if *pagamento > mes_compensacao {
+                    continue;
+                }
+
+                let fator = 1_f64 + taxa(*pagamento, mes_compensacao) / 100_f64;
+                let usado = (restante / fator).min(*saldo);
+
+                *saldo -= usado;
+                restante -= usado * fator;
+                original += usado;
+            }
+
+            movimentos.push(Movimento {
+                compensacao,
+                original,
+                saldo: saldos.iter().map(|(saldo, _)| saldo).sum(),
+                excedente: restante.max(0_f64),
+                fora_do_prazo: limite.is_some_and(|limite| {
+                    prescricao::data(&compensacao.data).expect("Data de compensação inválida.")
+                        > limite
+                }),
+            });
+        }
+
+        Extrato {
+            movimentos,
+            saldo_original: saldos.iter().map(|(saldo, _)| saldo).sum(),
+            saldo_atualizado: saldos
+                .iter()
+                .map(|(saldo, pagamento)| saldo * (1_f64 + taxa(*pagamento, data) / 100_f64))
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credito(competencia: &str, valor: f64) -> Credito {
+        Credito {
+            competencia: competencia.to_string(),
+            pis: valor * 0.18,
+            cofins: valor * 0.82,
+        }
+    }
+
+    fn compensacao(data: &str, valor: f64) -> Compensacao {
+        Compensacao {
+            data: data.to_string(),
+            debito: "IRPJ 2362".to_string(),
+            valor,
+        }
+    }
+
+    // SELIC of 1% a month from 01/2024 to 06/2024.
+    fn tabela() -> selic::Tabela {
+        selic::ler(
+            "01/2024;1\n02/2024;1\n03/2024;1\n04/2024;1\n05/2024;1\n06/2024;1\n",
+            false,
+        )
+    }
+
+    #[test]
+    fn compensacao_consome_o_credito_mais_antigo_e_passa_ao_seguinte() {
+        let razao = Razao {
+            creditos: vec![credito("11/2023", 1000_f64), credito("12/2023", 2000_f64)],
+            compensacoes: vec![compensacao("10/03/2024", 1500_f64)],
+        };
+
+        let extrato = razao.extrato(None, selic::mes("03/2024").unwrap(), None);
+
+        let movimento = &extrato.movimentos[0];
+        assert!((movimento.original - 1500_f64).abs() < 1e-9);
+        assert!((movimento.saldo - 1500_f64).abs() < 1e-9);
+        assert!(movimento.excedente.abs() < 1e-9);
+        assert!((extrato.saldo_original - 1500_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compensacao_acima_do_saldo_fica_sem_credito() {
+        let razao = Razao {
+            creditos: vec![credito("11/2023", 1000_f64)],
+            compensacoes: vec![compensacao("10/03/2024", 1200_f64)],
+        };
+
+        let extrato = razao.extrato(None, selic::mes("03/2024").unwrap(), None);
+
+        let movimento = &extrato.movimentos[0];
+        assert!((movimento.original - 1000_f64).abs() < 1e-9);
+        assert!((movimento.excedente - 200_f64).abs() < 1e-9);
+        assert!(extrato.saldo_original.abs() < 1e-9);
+    }
+
+    #[test]
+    fn compensacao_usa_o_valor_original_corrigido() {
+        let razao = Razao {
+            creditos: vec![credito("12/2023", 1000_f64)],
+            compensacoes: vec![compensacao("10/03/2024", 510_f64)],
+        };
+
+        // Paid in 01/2024, compensated in 03/2024: 1% of 02/2024 plus 1%.
+        let extrato = razao.extrato(Some(&tabela()), selic::mes("03/2024").unwrap(), None);
+
+        assert!((extrato.movimentos[0].original - 500_f64).abs() < 1e-9);
+        assert!((extrato.saldo_atualizado - 510_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn credito_pago_depois_da_compensacao_nao_e_usado() {
+        let razao = Razao {
+            creditos: vec![credito("03/2024", 1000_f64)],
+            compensacoes: vec![compensacao("20/03/2024", 300_f64)],
+        };
+
+        // Paid in 04/2024, after the compensation.
+        let extrato = razao.extrato(Some(&tabela()), selic::mes("05/2024").unwrap(), None);
+
+        let movimento = &extrato.movimentos[0];
+        assert!(movimento.original.abs() < 1e-9);
+        assert!((movimento.excedente - 300_f64).abs() < 1e-9);
+        assert!((extrato.saldo_original - 1000_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn correcao_para_no_mes_seguinte_a_ultima_taxa() {
+        let razao = Razao {
+            creditos: vec![credito("12/2023", 1000_f64)],
+            compensacoes: vec![compensacao("10/10/2024", 100_f64)],
+        };
+
+        // From 02/2024 to 06/2024, plus 1% in 07/2024, with no rate after 06/2024.
+        let extrato = razao.extrato(Some(&tabela()), selic::mes("10/2024").unwrap(), None);
+
+        assert!((extrato.movimentos[0].original - 100_f64 / 1.06).abs() < 1e-9);
+        assert!((extrato.saldo_atualizado - (1000_f64 - 100_f64 / 1.06) * 1.06).abs() < 1e-9);
+    }
+
+    #[test]
+    fn registrar_substitui_e_remover_descarta() {
+        let mut razao = Razao::default();
+        razao.registrar("31122023", 10_f64, 46_f64);
+        razao.registrar("30112023", 20_f64, 92_f64);
+        razao.registrar("31122023", 5_f64, 23_f64);
+
+        let competencias: Vec<&str> = razao
+            .creditos
+            .iter()
+            .map(|credito| credito.competencia.as_str())
+            .collect();
+        assert_eq!(competencias, ["11/2023", "12/2023"]);
+        assert_eq!(razao.creditos[1].pis, 5_f64);
+
+        razao.remover("30112023");
+        assert_eq!(razao.creditos.len(), 1);
+        assert_eq!(razao.creditos[0].competencia, "12/2023");
+    }
+}
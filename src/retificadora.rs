@@ -0,0 +1,513 @@
+use encoding_rs::*;
+
+use crate::ajuste::{self, Exclusao};
+use crate::config;
+use crate::efd_contribuicoes::{self, to_f64};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// A line of the original file. Lines that aren't changed are written back as they were read.
+struct Linha {
+    // Position in the original file, None for the registers added.
+    original: Option<usize>,
+    bytes: Vec<u8>,
+    eol: Vec<u8>,
+    texto: String,
+    alterada: bool,
+}
+
+impl Linha {
+    fn new(texto: String, eol: &[u8]) -> Self {
+        Self {
+            original: None,
+            bytes: Vec::new(),
+            eol: eol.to_vec(),
+            texto,
+            alterada: true,
+        }
+    }
+
+    fn campos(&self) -> Vec<String> {
+        self.texto.split('|').map(|s| s.to_string()).collect()
+    }
+
+    // Empty for lines that aren't registers, such as the signature after 9999.
+    fn reg(&self) -> &str {
+        if self.texto.starts_with('|') {
+            self.texto.split('|').nth(1).unwrap_or_default()
+        } else {
+            ""
+        }
+    }
+
+    fn alterar(&mut self, campos: &[String]) {
+        let texto = campos.join("|");
+        if texto != self.texto {
+            self.texto = texto;
+            self.alterada = true;
+        }
+    }
+}
+
+fn arredondar(value: f64) -> f64 {
+    (value * 100_f64).round() / 100_f64
+}
+
+// Sets a value field only when the amount changes, so fields filed as "0" keep their text.
+fn recalcular(campos: &mut [String], index: usize, value: f64) {
+    if (to_f64(&campos.get(index)) - value).abs() >= 0.005 {
+        campos[index] = ajuste::valor(value);
+    }
+}
+
+// Applies the reduction to an M210/M610 line and returns the change in VL_CONT_PER. From 2019 the
+// reduction goes to VL_AJUS_REDUC_BC; the older layout has no base adjustments, so VL_BC_CONT
+// itself is reduced.
+pub(crate) fn apurar(campos: &mut [String], vl_ajus: f64) -> f64 {
+    let (bc, aliq, quant, aliq_quant, apur, per) = if campos.len() == 15 {
+        campos[4] = ajuste::valor(to_f64(&campos.get(4)) - vl_ajus);
+        (4, 5, 6, 7, 8, 13)
+    } else {
+        let reduc = to_f64(&campos.get(6)) + vl_ajus;
+        campos[6] = ajuste::valor(reduc);
+        campos[7] = ajuste::valor(to_f64(&campos.get(4)) + to_f64(&campos.get(5)) - reduc);
+        (7, 8, 9, 10, 11, 16)
+    };
+
+    let vl_cont_apur = arredondar(
+        to_f64(&campos.get(bc)) * to_f64(&campos.get(aliq)) / 100_f64
+            + to_f64(&campos.get(quant)) * to_f64(&campos.get(aliq_quant)),
+    );
+    let vl_cont_per = vl_cont_apur + to_f64(&campos.get(apur + 1))
+        - to_f64(&campos.get(apur + 2))
+        - to_f64(&campos.get(apur + 3))
+        + to_f64(&campos.get(apur + 4));
+
+    let anterior = to_f64(&campos.get(per));
+    campos[apur] = ajuste::valor(vl_cont_apur);
+    campos[per] = ajuste::valor(vl_cont_per);
+
+    vl_cont_per - anterior
+}
+
+// Writes a copy of the EFD Contribuições set up as a retifying file, with the M215/M615 reductions
+// of the exclusion and the registers that depend on them recalculated, or tells why it can't.
+pub(crate) fn gerar(
+    original: &Path,
+    destino: &Path,
+    config: &config::Ajuste,
+    competencia: &str,
+    exclusoes: &[Exclusao],
+    num_rec_anterior: &str,
+) -> Result<(), String> {
+    let bytes = fs::read(original).expect("Não foi possível ler a EFD Contribuições.");
+
+    let saida = retificar(&bytes, config, competencia, exclusoes, num_rec_anterior)?;
+
+    fs::write(destino, saida).expect("Não foi possível gravar a EFD Contribuições retificadora.");
+
+    Ok(())
+}
+
+// The retifying file from the bytes of the original. A contribution that would
+// end up below the credits, withholdings or deductions already taken is refused, since those come
+// from registers (M100/M500, F600, 1300) that are not recalculated here.
+fn retificar(
+    bytes: &[u8],
+    config: &config::Ajuste,
+    competencia: &str,
+    exclusoes: &[Exclusao],
+    num_rec_anterior: &str,
+) -> Result<Vec<u8>, String> {
+    let mut linhas: Vec<Linha> = Vec::new();
+    for (numero, raw) in bytes.split_inclusive(|&b| b == b'\n').enumerate() {
+        let fim = raw
+            .iter()
+            .rposition(|&b| b != b'\r' && b != b'\n')
+            .map_or(0, |i| i + 1);
+        let (conteudo, eol) = raw.split_at(fim);
+        let (texto, _) = WINDOWS_1252.decode_without_bom_handling(conteudo);
+
+        linhas.push(Linha {
+            original: Some(numero),
+            bytes: conteudo.to_vec(),
+            eol: eol.to_vec(),
+            texto: texto.to_string(),
+            alterada: false,
+        });
+    }
+
+    // Change of VL_CONT_PER by contribution and by non-cumulative (true) or cumulative.
+    let mut variacao: HashMap<(&str, bool), f64> = HashMap::new();
+
+    let mut i = 0;
+    while i < linhas.len() {
+        let reg = linhas[i].reg().to_string();
+
+        match reg.as_str() {
+            "0000" => {
+                let mut campos = linhas[i].campos();
+                campos[3] = "1".to_string();
+                campos[5] = num_rec_anterior.to_string();
+                linhas[i].alterar(&campos);
+            }
+
+            "M210" | "M610" => {
+                let (tributo, reg_ajuste) = if reg == "M210" {
+                    ("PIS", "M215")
+                } else {
+                    ("COFINS", "M615")
+                };
+
+                // Identical M210/M610 lines are told apart by their position.
+                let exclusao = exclusoes.iter().find(|exclusao| {
+                    exclusao.reg == reg_ajuste
+                        && Some(exclusao.apuracao.numero) == linhas[i].original
+                });
+
+                if let Some(exclusao) = exclusao {
+                    let ajustes = exclusao.ajustes(config, competencia);
+                    let vl_ajus: f64 = ajustes.iter().map(|(valor, _)| valor).sum();
+
+                    // Lines without a reduction keep their values as filed.
+                    if ajustes.is_empty() {
+                        i += 1;
+                        continue;
+                    }
+
+                    let mut campos = linhas[i].campos();
+                    let layout_2019 = !exclusao.apuracao.layout_antigo();
+                    let delta = apurar(&mut campos, vl_ajus);
+                    let nao_cumulativo = !efd_contribuicoes::cumulativo(&campos[2]);
+                    linhas[i].alterar(&campos);
+
+                    *variacao.entry((tributo, nao_cumulativo)).or_insert(0_f64) += delta;
+
+                    if layout_2019 {
+                        // After the M211 and any M215/M615 already in the file.
+                        let eol = linhas[i].eol.clone();
+                        let mut j = i + 1;
+                        while j < linhas.len()
+                            && (matches!(linhas[j].reg(), "M211" | "M611")
+                                || linhas[j].reg() == reg_ajuste)
+                        {
+                            j += 1;
+                        }
+                        for (_, registro) in ajustes.into_iter().rev() {
+                            linhas.insert(j, Linha::new(registro, &eol));
+                        }
+                    }
+                }
+            }
+
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    // M200/M600 and the M205/M605 debts by revenue code.
+    for (tributo, reg_total, reg_debito) in [("PIS", "M200", "M205"), ("COFINS", "M600", "M605")] {
+        let nc = *variacao.get(&(tributo, true)).unwrap_or(&0_f64);
+        let cum = *variacao.get(&(tributo, false)).unwrap_or(&0_f64);
+
+        let mut anterior: HashMap<&str, f64> = HashMap::new();
+        let mut atual: HashMap<&str, f64> = HashMap::new();
+
+        for linha in linhas.iter_mut().filter(|linha| linha.reg() == reg_total) {
+            let mut campos = linha.campos();
+            anterior.insert("08", to_f64(&campos.get(8)));
+            anterior.insert("12", to_f64(&campos.get(12)));
+
+            let nc_per = to_f64(&campos.get(2)) + nc;
+            let nc_dev = nc_per - to_f64(&campos.get(3)) - to_f64(&campos.get(4));
+            let nc_rec = nc_dev - to_f64(&campos.get(6)) - to_f64(&campos.get(7));
+            let cum_per = to_f64(&campos.get(9)) + cum;
+            let cum_rec = cum_per - to_f64(&campos.get(10)) - to_f64(&campos.get(11));
+
+            if nc_dev < -0.005 || nc_rec < -0.005 || cum_rec < -0.005 {
+                return Err(format!(
+                    "{}: com a redução, a contribuição ficaria menor que os créditos, retenções e deduções já descontados; revisar M100/M500 e {} antes de retificar",
+                    reg_total, reg_total
+                ));
+            }
+
+            recalcular(&mut campos, 2, nc_per);
+            recalcular(&mut campos, 5, nc_dev);
+            recalcular(&mut campos, 8, nc_rec);
+            recalcular(&mut campos, 9, cum_per);
+            recalcular(&mut campos, 12, cum_rec);
+            recalcular(&mut campos, 13, nc_rec + cum_rec);
+            linha.alterar(&campos);
+
+            atual.insert("08", nc_rec);
+            atual.insert("12", cum_rec);
+        }
+
+        // The debts of each field are reduced in proportion, the last one taking the rounding.
+        for num_campo in ["08", "12"] {
+            let antes = *anterior.get(num_campo).unwrap_or(&0_f64);
+            let depois = *atual.get(num_campo).unwrap_or(&0_f64);
+
+            let mut debitos: Vec<&mut Linha> = linhas
+                .iter_mut()
+                .filter(|linha| linha.reg() == reg_debito)
+                .filter(|linha| linha.campos().get(2).map(String::as_str) == Some(num_campo))
+                .collect();
+
+            if debitos.is_empty() || antes <= 0_f64 {
+                continue;
+            }
+
+            let mut restante = depois;
+            let ultimo = debitos.len() - 1;
+            for (index, linha) in debitos.iter_mut().enumerate() {
+                let mut campos = linha.campos();
+                let vl_debito = if index == ultimo {
+                    restante
+                } else {
+                    arredondar(to_f64(&campos.get(4)) * depois / antes)
+                };
+                restante -= vl_debito;
+                recalcular(&mut campos, 4, vl_debito);
+                linha.alterar(&campos);
+            }
+        }
+    }
+
+    // 9900 of the new registers, right after the ones of M210/M610.
+    for (reg, reg_anterior) in [("M215", "M210"), ("M615", "M610")] {
+        let existe = linhas
+            .iter()
+            .any(|linha| linha.reg() == "9900" && linha.campos()[2] == reg);
+        let usado = linhas.iter().any(|linha| linha.reg() == reg);
+
+        if existe || !usado {
+            continue;
+        }
+
+        if let Some(index) = linhas
+            .iter()
+            .position(|linha| linha.reg() == "9900" && linha.campos()[2] == reg_anterior)
+        {
+            let eol = linhas[index].eol.clone();
+            linhas.insert(index + 1, Linha::new(format!("|9900|{}|0|", reg), &eol));
+        }
+    }
+
+    // Register and block counts.
+    let mut contagem: HashMap<String, usize> = HashMap::new();
+    for linha in linhas.iter() {
+        *contagem.entry(linha.reg().to_string()).or_insert(0) += 1;
+    }
+    let bloco = |letra: char| {
+        contagem
+            .iter()
+            .filter(|(reg, _)| reg.starts_with(letra))
+            .map(|(_, quantidade)| quantidade)
+            .sum::<usize>()
+    };
+    let (bloco_m, bloco_9) = (bloco('M'), bloco('9'));
+    let total = linhas
+        .iter()
+        .filter(|linha| !linha.reg().is_empty())
+        .count();
+
+    for linha in linhas.iter_mut() {
+        let mut campos = linha.campos();
+        match linha.reg() {
+            "9900" => campos[3] = contagem.get(&campos[2]).unwrap_or(&0).to_string(),
+            "M990" => campos[2] = bloco_m.to_string(),
+            "9990" => campos[2] = bloco_9.to_string(),
+            "9999" => campos[2] = total.to_string(),
+            _ => continue,
+        }
+        linha.alterar(&campos);
+    }
+
+    let mut saida: Vec<u8> = Vec::with_capacity(bytes.len());
+    for linha in linhas {
+        if linha.alterada {
+            let (texto, _, _) = WINDOWS_1252.encode(&linha.texto);
+            saida.extend_from_slice(&texto);
+        } else {
+            saida.extend_from_slice(&linha.bytes);
+        }
+        saida.extend_from_slice(&linha.eol);
+    }
+
+    Ok(saida)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::efd_contribuicoes::Apuracao;
+
+    fn config() -> config::Ajuste {
+        config::Ajuste {
+            cod_aj_bc: "41".to_string(),
+            cod_cta: "3.1.1.01.001".to_string(),
+            retificadora: true,
+        }
+    }
+
+    fn apuracao(linhas: &[&str], numero: usize) -> Apuracao {
+        Apuracao {
+            cod_cont: "01".to_string(),
+            vl_rec_brt: 100000_f64,
+            vl_bc_cont: 100000_f64,
+            aliq_cont: 1.65,
+            linha: linhas[numero].to_string(),
+            numero,
+            vl_ajus_icms: 0_f64,
+        }
+    }
+
+    // The retifying file of the lines of an EFD Contribuições, line by line.
+    fn gerar_linhas(linhas: &[&str], exclusoes: &[Exclusao]) -> Vec<String> {
+        let mut texto = linhas.join("\r\n");
+        texto.push_str("\r\n");
+
+        let bytes =
+            retificar(texto.as_bytes(), &config(), "31012023", exclusoes, "123456").unwrap();
+        let (saida, _) = WINDOWS_1252.decode_without_bom_handling(&bytes);
+
+        saida.lines().map(|linha| linha.to_string()).collect()
+    }
+
+    const LAYOUT_2019: [&str; 10] = [
+        "|0000|006|0||000000|01012023|31012023|EMPRESA|11111111000191|SP|3550308||00|2|",
+        "|M001|0|",
+        "|M200|1650,00|0|0|1650,00|0|0|1650,00|0|0|0|0|1650,00|",
+        "|M205|08|691201|1650,00|",
+        "|M210|01|100000,00|100000,00|0|0|100000,00|1,6500|0|0|1650,00|0|0|0|0|1650,00|",
+        "|M990|5|",
+        "|9001|0|",
+        "|9900|M210|1|",
+        "|9990|4|",
+        "|9999|10|",
+    ];
+
+    #[test]
+    fn layout_2019_recebe_m215_e_recalcula_totais() {
+        let apuracao = apuracao(&LAYOUT_2019, 4);
+        let exclusoes = [Exclusao {
+            reg: "M215",
+            apuracao: &apuracao,
+            vl_icms: 10000_f64,
+            filiais: vec![("11111111000191".to_string(), 1_f64)],
+        }];
+
+        let linhas = gerar_linhas(&LAYOUT_2019, &exclusoes);
+
+        assert!(linhas[0].starts_with("|0000|006|1||123456|"));
+        assert_eq!(
+            linhas[4],
+            "|M210|01|100000,00|100000,00|0|10000,00|90000,00|1,6500|0|0|1485,00|0|0|0|0|1485,00|"
+        );
+        assert_eq!(
+            linhas[5],
+            "|M215|0|10000,00|41||Exclusão do ICMS da base de cálculo (RE 574.706)|31012023|3.1.1.01.001|11111111000191||"
+        );
+        assert_eq!(
+            linhas[2],
+            "|M200|1485,00|0|0|1485,00|0|0|1485,00|0|0|0|0|1485,00|"
+        );
+        assert_eq!(linhas[3], "|M205|08|691201|1485,00|");
+        assert!(linhas.contains(&"|9900|M215|1|".to_string()));
+        assert_eq!(linhas[linhas.len() - 1], "|9999|12|");
+    }
+
+    #[test]
+    fn layout_antigo_reduz_vl_bc_cont_sem_m215() {
+        let texto: Vec<&str> = LAYOUT_2019
+            .iter()
+            .map(|linha| {
+                if linha.starts_with("|M210|") {
+                    "|M210|01|100000,00|100000,00|1,6500|0|0|1650,00|0|0|0|0|1650,00|"
+                } else {
+                    linha
+                }
+            })
+            .collect();
+        let apuracao = apuracao(&texto, 4);
+        assert!(apuracao.layout_antigo());
+
+        let exclusoes = [Exclusao {
+            reg: "M215",
+            apuracao: &apuracao,
+            vl_icms: 10000_f64,
+            filiais: vec![("11111111000191".to_string(), 1_f64)],
+        }];
+
+        let linhas = gerar_linhas(&texto, &exclusoes);
+
+        assert_eq!(
+            linhas[4],
+            "|M210|01|100000,00|90000,00|1,6500|0|0|1485,00|0|0|0|0|1485,00|"
+        );
+        assert!(!linhas.iter().any(|linha| linha.starts_with("|M215|")));
+        assert_eq!(linhas[3], "|M205|08|691201|1485,00|");
+    }
+
+    #[test]
+    fn linhas_iguais_sao_distinguidas_pela_posicao_e_ajuste_zero_nao_altera() {
+        let mut texto: Vec<&str> = LAYOUT_2019.to_vec();
+        texto.insert(5, LAYOUT_2019[4]);
+        let primeira = apuracao(&texto, 4);
+        let segunda = apuracao(&texto, 5);
+
+        let exclusoes = [
+            Exclusao {
+                reg: "M215",
+                apuracao: &primeira,
+                vl_icms: 0_f64,
+                filiais: vec![("11111111000191".to_string(), 1_f64)],
+            },
+            Exclusao {
+                reg: "M215",
+                apuracao: &segunda,
+                vl_icms: 10000_f64,
+                filiais: vec![("11111111000191".to_string(), 1_f64)],
+            },
+        ];
+
+        let linhas = gerar_linhas(&texto, &exclusoes);
+
+        assert_eq!(linhas[4], LAYOUT_2019[4]);
+        assert_eq!(
+            linhas[5],
+            "|M210|01|100000,00|100000,00|0|10000,00|90000,00|1,6500|0|0|1485,00|0|0|0|0|1485,00|"
+        );
+        assert!(linhas[6].starts_with("|M215|"));
+    }
+
+    #[test]
+    fn contribuicao_menor_que_os_creditos_descontados_e_recusada() {
+        let mut texto: Vec<&str> = LAYOUT_2019.to_vec();
+        texto[2] = "|M200|1650,00|1600,00|0|50,00|0|0|50,00|0|0|0|0|50,00|";
+        texto[3] = "|M205|08|691201|50,00|";
+        let apuracao = apuracao(&texto, 4);
+        let exclusoes = [Exclusao {
+            reg: "M215",
+            apuracao: &apuracao,
+            vl_icms: 10000_f64,
+            filiais: vec![("11111111000191".to_string(), 1_f64)],
+        }];
+
+        let texto = texto.join("\r\n");
+        let erro = retificar(
+            texto.as_bytes(),
+            &config(),
+            "31012023",
+            &exclusoes,
+            "123456",
+        )
+        .unwrap_err();
+
+        assert!(erro.starts_with("M200:"));
+    }
+}
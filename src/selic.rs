@@ -0,0 +1,162 @@
+use crate::efd_contribuicoes::decimal;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Monthly SELIC rates (%), keyed by month index (see `mes`).
+pub(crate) struct Tabela {
+    taxas: HashMap<u32, f64>,
+}
+
+// Month as a single index (aaaa * 12 + mm - 1), from "mm/aaaa", "dd/mm/aaaa", "aaaa-mm" or DT_FIN
+// (ddmmaaaa).
+pub(crate) fn mes(text: &str) -> Option<u32> {
+    let text = text.trim();
+
+    let (ano, mes) = if let Some((inicio, ano)) = text.rsplit_once('/') {
+        (ano, inicio.rsplit('/').next()?)
+    } else if let Some((ano, mes)) = text.split_once('-') {
+        (ano, mes)
+    } else if text.len() == 8 && text.is_ascii() {
+        (&text[4..8], &text[2..4])
+    } else {
+        return None;
+    };
+
+    let ano: u32 = ano.parse().ok()?;
+    let mes: u32 = mes.parse().ok()?;
+
+    if (1..=12).contains(&mes) {
+        Some(ano * 12 + mes - 1)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn mes_to_string(mes: u32) -> String {
+    format!("{:02}/{}", mes % 12 + 1, mes / 12)
+}
+
+// Loads the table from a YAML map ("mm/aaaa": taxa) or from a CSV with "mm/aaaa;taxa" lines. Lines
+// that aren't a month and a rate, such as headers, are skipped.
+pub(crate) fn load(path: &Path) -> Tabela {
+    let text = fs::read_to_string(path).expect("Não foi possível ler a tabela SELIC.");
+
+    let yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    );
+
+    ler(&text, yaml)
+}
+
+pub(crate) fn ler(text: &str, yaml: bool) -> Tabela {
+    let mut taxas: HashMap<u32, f64> = HashMap::new();
+
+    if yaml {
+        let valores: HashMap<String, f64> =
+            serde_yaml::from_str(text).expect("Tabela SELIC inválida.");
+        for (key, taxa) in valores {
+            let mes = mes(&key).expect("Tabela SELIC inválida.");
+            taxas.insert(mes, taxa);
+        }
+    } else {
+        for line in text.lines() {
+            let data: Vec<&str> = line.split(';').collect();
+
+            if let (Some(mes), Some(taxa)) = (
+                data.first().and_then(|value| mes(value)),
+                data.get(1).and_then(|value| decimal(value.trim())),
+            ) {
+                taxas.insert(mes, taxa);
+            }
+        }
+    }
+
+    Tabela { taxas }
+}
+
+impl Tabela {
+    pub(crate) fn ultimo(&self) -> Option<u32> {
+        self.taxas.keys().max().copied()
+    }
+
+    // Interest (%) on an amount paid in `pagamento` and recovered in `data`: the SELIC accumulated
+    // from the month after the payment to the month before `data`, plus 1% for the month of `data`
+    // (Lei 9.250/1995, art. 39, § 4º).
+    pub(crate) fn acumulada(&self, pagamento: u32, data: u32) -> f64 {
+        if data <= pagamento {
+            return 0_f64;
+        }
+
+        let soma: f64 = (pagamento + 1..data)
+            .map(|mes| {
+                *self.taxas.get(&mes).unwrap_or_else(|| {
+                    panic!("Taxa SELIC de {} não encontrada.", mes_to_string(mes))
+                })
+            })
+            .sum();
+
+        soma + 1_f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tabela() -> Tabela {
+        Tabela {
+            taxas: HashMap::from([
+                (mes("01/2024").unwrap(), 0.97),
+                (mes("02/2024").unwrap(), 0.80),
+                (mes("03/2024").unwrap(), 0.83),
+            ]),
+        }
+    }
+
+    #[test]
+    fn mes_aceita_todos_os_formatos() {
+        let marco = 2017 * 12 + 2;
+        assert_eq!(mes("03/2017"), Some(marco));
+        assert_eq!(mes("15/03/2017"), Some(marco));
+        assert_eq!(mes("2017-03"), Some(marco));
+        assert_eq!(mes("31032017"), Some(marco));
+        assert_eq!(mes("13/2017"), None);
+        assert_eq!(mes("Período"), None);
+        assert_eq!(mes_to_string(marco), "03/2017");
+    }
+
+    #[test]
+    fn acumulada_soma_os_meses_seguintes_ao_pagamento_e_mais_um() {
+        let pagamento = mes("12/2023").unwrap();
+        let data = mes("04/2024").unwrap();
+
+        assert!((tabela().acumulada(pagamento, data) - 3.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn acumulada_no_mes_seguinte_ao_pagamento_e_so_um() {
+        let pagamento = mes("12/2023").unwrap();
+
+        assert!((tabela().acumulada(pagamento, pagamento + 1) - 1_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn acumulada_sem_correcao_ate_o_pagamento() {
+        let pagamento = mes("02/2024").unwrap();
+
+        assert_eq!(tabela().acumulada(pagamento, pagamento), 0_f64);
+        assert_eq!(tabela().acumulada(pagamento, pagamento - 1), 0_f64);
+    }
+
+    #[test]
+    #[should_panic(expected = "Taxa SELIC de 04/2024 não encontrada.")]
+    fn acumulada_sem_taxa_do_mes() {
+        let pagamento = mes("12/2023").unwrap();
+        let data = mes("05/2024").unwrap();
+
+        tabela().acumulada(pagamento, data);
+    }
+}